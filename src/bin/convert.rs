@@ -1,12 +1,135 @@
+use anyhow::{Result, anyhow, bail};
+use std::path::{Path, PathBuf};
+use tch::{Device, Kind, Tensor};
+use vdb::embeddings::checkpoint::{read_tensors, remap_names, weights_file, write_tensors};
+use vdb::embeddings::{EmbeddingConfig, SentenceTransformer};
+
+const USAGE: &str = "usage:
+  convert convert <source> <destination> [--prefix <name>]
+      Converts between .npz, .safetensors and .ot checkpoints, renaming
+      Hugging Face parameters to the rust-bert layout. Bare encoder weights
+      are nested under the prefix (bert, distilbert, roberta or mpnet).
+  convert inspect <checkpoint>
+      Lists tensor names, shapes and dtypes.
+  convert verify [model_dir]
+      Loads the model (default: the configured VDB_MODEL_DIR) and embeds a
+      test sentence.
+  convert cast <source> <destination> <f16|bf16|f32>
+      Converts every floating point tensor to the given dtype.";
+
 pub fn main() {
-    let args: Vec<_> = std::env::args().collect();
-    if !args.len() == 3 {
-        eprintln!("usage: {} source.npz destination.ot", args[0]);
+    let args: Vec<String> = std::env::args().skip(1).collect();
+    let args: Vec<&str> = args.iter().map(String::as_str).collect();
+    let result = match args.as_slice() {
+        ["convert", source, destination] => convert(source, destination, None),
+        ["convert", source, destination, "--prefix", prefix] => {
+            convert(source, destination, Some(*prefix))
+        }
+        ["inspect", checkpoint] => inspect(checkpoint),
+        ["verify"] => verify(None),
+        ["verify", model_dir] => verify(Some(*model_dir)),
+        ["cast", source, destination, dtype] => cast(source, destination, dtype),
+        _ => {
+            eprintln!("{}", USAGE);
+            std::process::exit(1);
+        }
+    };
+    if let Err(e) = result {
+        eprintln!("error: {:#}", e);
         std::process::exit(1);
     }
+}
+
+/// Reads `path`, which may also be a model directory holding one of the
+/// weights files `load_weights` accepts.
+fn read_checkpoint(path: &str) -> Result<Vec<(String, Tensor)>> {
+    let path = Path::new(path);
+    let file = if path.is_dir() {
+        weights_file(path).ok_or_else(|| anyhow!("no weights file in {}", path.display()))?
+    } else {
+        path.to_path_buf()
+    };
+    Ok(read_tensors(&file, Device::Cpu)?)
+}
+
+fn convert(source: &str, destination: &str, prefix: Option<&str>) -> Result<()> {
+    let tensors = remap_names(read_checkpoint(source)?, prefix);
+    write_tensors(Path::new(destination), &tensors)?;
+    println!("wrote {} tensors to {}", tensors.len(), destination);
+    Ok(())
+}
+
+fn inspect(checkpoint: &str) -> Result<()> {
+    let mut tensors = read_checkpoint(checkpoint)?;
+    tensors.sort_by(|(a, _), (b, _)| a.cmp(b));
+
+    let mut parameters = 0;
+    let mut bytes = 0;
+    for (name, tensor) in &tensors {
+        println!("{}\t{:?}\t{:?}", name, tensor.size(), tensor.kind());
+        parameters += tensor.numel();
+        bytes += tensor.numel() * tensor.kind().elt_size_in_bytes();
+    }
+    println!(
+        "{} tensors, {} parameters, {:.1} MiB",
+        tensors.len(),
+        parameters,
+        bytes as f64 / (1024.0 * 1024.0)
+    );
+    Ok(())
+}
+
+fn verify(model_dir: Option<&str>) -> Result<()> {
+    let mut config = EmbeddingConfig::from_env().map_err(anyhow::Error::msg)?;
+    if let Some(model_dir) = model_dir {
+        config.model_dir = PathBuf::from(model_dir);
+    }
+    let model = SentenceTransformer::from_config(&config)?;
+
+    let embedding = model.encode("The quick brown fox jumps over the lazy dog.");
+    if embedding.len() != model.output_dimension() {
+        bail!(
+            "embedding has {} dimensions, expected {}",
+            embedding.len(),
+            model.output_dimension()
+        );
+    }
+    if embedding.iter().any(|x| !x.is_finite()) {
+        bail!("embedding contains NaN or infinite values");
+    }
+    println!(
+        "{}: {:?} encoder, {} modules, {} dimensions",
+        config.model_dir.display(),
+        model.bert.model_type,
+        model.modules.len(),
+        embedding.len()
+    );
+    Ok(())
+}
 
-    let source_file = &args[1];
-    let destination_file = &args[2];
-    let tensors = tch::Tensor::read_npz(source_file).unwrap();
-    tch::Tensor::save_multi(&tensors, destination_file).unwrap();
+fn cast(source: &str, destination: &str, dtype: &str) -> Result<()> {
+    let kind = match dtype {
+        "f16" => Kind::Half,
+        "bf16" => Kind::BFloat16,
+        "f32" => Kind::Float,
+        _ => bail!("unsupported dtype `{}` (expected f16, bf16 or f32)", dtype),
+    };
+    let tensors: Vec<(String, Tensor)> = read_checkpoint(source)?
+        .into_iter()
+        .map(|(name, tensor)| {
+            if tensor.is_floating_point() {
+                (name, tensor.to_kind(kind))
+            } else {
+                (name, tensor)
+            }
+        })
+        .collect();
+    write_tensors(Path::new(destination), &tensors)?;
+    println!(
+        "wrote {} tensors as {} to {}",
+        tensors.len(),
+        dtype,
+        destination
+    );
+    Ok(())
 }
@@ -0,0 +1,258 @@
+use crate::database::db::{
+    Database, DatabaseOperations, Document, ListOptions, Page, QueryOptions, SharedDatabase,
+    UpsertOutcome,
+};
+use std::time::Duration;
+use tokio::task::{self, JoinHandle};
+
+/// Async facade over a `SharedDatabase`.
+///
+/// Every call runs on tokio's blocking pool, so embedding and scoring never
+/// stall the async runtime. Dropping a returned future, or hitting the
+/// request timeout, aborts the call if it has not started yet; a call that
+/// is already running finishes in the background and its result is dropped.
+///
+/// A write that times out may therefore still be applied, and its error
+/// says so; retry it with the same `request_id` through `upsert` or
+/// `upsert_batch` to have it applied exactly once.
+#[derive(Clone)]
+pub struct AsyncDatabase {
+    inner: SharedDatabase,
+    timeout: Option<Duration>,
+}
+
+impl AsyncDatabase {
+    pub fn new(inner: SharedDatabase) -> AsyncDatabase {
+        AsyncDatabase {
+            inner,
+            timeout: None,
+        }
+    }
+
+    /// Returns a handle to the same database whose calls fail once they take
+    /// longer than `timeout`.
+    pub fn timeout(&self, timeout: Duration) -> AsyncDatabase {
+        AsyncDatabase {
+            inner: self.inner.clone(),
+            timeout: Some(timeout),
+        }
+    }
+
+    pub fn inner(&self) -> &SharedDatabase {
+        &self.inner
+    }
+
+    pub async fn load(&self, texts: Vec<String>) -> Result<(), String> {
+        self.write(move |db| db.load(&texts)).await
+    }
+
+    pub async fn query(&self, query: String, n: u32) -> Result<Vec<Document>, String> {
+        self.run(move |db| Ok(db.query(query, n))).await
+    }
+
+    pub async fn query_with(
+        &self,
+        query: String,
+        options: QueryOptions,
+    ) -> Result<Vec<Document>, String> {
+        self.run(move |db| Ok(db.query_with(query, &options))).await
+    }
+
+    pub async fn query_batch(
+        &self,
+        queries: Vec<String>,
+        n: u32,
+    ) -> Result<Vec<Vec<Document>>, String> {
+        self.run(move |db| Ok(db.query_batch(&queries, n))).await
+    }
+
+    pub async fn insert(&self, document: Document) -> Result<(), String> {
+        self.write(move |db| db.insert(document)).await
+    }
+
+    pub async fn update(&self, document: Document) -> Result<(), String> {
+        self.write(move |db| db.update(document)).await
+    }
+
+    pub async fn upsert(
+        &self,
+        document: Document,
+        request_id: Option<String>,
+    ) -> Result<UpsertOutcome, String> {
+        self.write(move |db| db.upsert(document, request_id)).await
+    }
+
+    pub async fn upsert_batch(
+        &self,
+        documents: Vec<Document>,
+        request_id: Option<String>,
+    ) -> Result<Vec<UpsertOutcome>, String> {
+        self.write(move |db| db.upsert_batch(documents, request_id))
+            .await
+    }
+
+    pub async fn delete(&self, id: String) -> Result<(), String> {
+        self.write(move |db| db.delete(&id)).await
+    }
+
+    pub async fn get(&self, id: String) -> Result<Document, String> {
+        self.run(move |db| db.get(&id)).await
+    }
+
+    pub async fn get_metadata(&self, id: String) -> Result<Vec<String>, String> {
+        self.run(move |db| db.get_metadata(&id)).await
+    }
+
+    pub async fn list(&self) -> Result<Vec<Document>, String> {
+        self.run(|db| db.list()).await
+    }
+
+    pub async fn list_page(&self, options: ListOptions) -> Result<Page, String> {
+        self.run(move |db| db.list_page(&options)).await
+    }
+
+    pub async fn count(&self) -> Result<usize, String> {
+        self.run(|db| db.count()).await
+    }
+
+    pub async fn clear(&self) -> Result<(), String> {
+        self.write(|db| db.clear()).await
+    }
+
+    async fn run<T, F>(&self, operation: F) -> Result<T, String>
+    where
+        F: FnOnce(&Database) -> Result<T, String> + Send + 'static,
+        T: Send + 'static,
+    {
+        self.spawn(operation, "Request timed out").await
+    }
+
+    async fn write<T, F>(&self, operation: F) -> Result<T, String>
+    where
+        F: FnOnce(&Database) -> Result<T, String> + Send + 'static,
+        T: Send + 'static,
+    {
+        self.spawn(operation, "Write timed out and may still be applied")
+            .await
+    }
+
+    async fn spawn<T, F>(&self, operation: F, timed_out: &str) -> Result<T, String>
+    where
+        F: FnOnce(&Database) -> Result<T, String> + Send + 'static,
+        T: Send + 'static,
+    {
+        let db = self.inner.clone();
+        let mut task = AbortOnDrop(task::spawn_blocking(move || operation(&db)));
+        let joined = match self.timeout {
+            Some(timeout) => tokio::time::timeout(timeout, &mut task.0)
+                .await
+                .map_err(|_| format!("{} after {:?}", timed_out, timeout))?,
+            None => (&mut task.0).await,
+        };
+        joined.map_err(|e| e.to_string())?
+    }
+}
+
+/// Aborts the wrapped blocking task when the awaiting future goes away.
+struct AbortOnDrop<T>(JoinHandle<T>);
+
+impl<T> Drop for AbortOnDrop<T> {
+    fn drop(&mut self) {
+        self.0.abort();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::database::cosine::CosineDatabase;
+    use crate::embeddings::{Embedder, HashingEmbedder};
+    use std::sync::Arc;
+    use std::thread;
+    use tokio::runtime::{Builder, Runtime};
+
+    /// Takes `delay` for every batch it embeds.
+    struct SlowEmbedder {
+        inner: HashingEmbedder,
+        delay: Duration,
+    }
+
+    impl Embedder for SlowEmbedder {
+        fn embed_batch(&self, texts: &[&str]) -> Vec<Vec<f64>> {
+            thread::sleep(self.delay);
+            self.inner.embed_batch(texts)
+        }
+
+        fn dimension(&self) -> usize {
+            self.inner.dimension()
+        }
+
+        fn model_id(&self) -> String {
+            self.inner.model_id()
+        }
+    }
+
+    fn slow_database(delay: Duration) -> AsyncDatabase {
+        let embedder = SlowEmbedder {
+            inner: HashingEmbedder::new(64),
+            delay,
+        };
+        let db = Database::CosineDatabase(CosineDatabase::new(Box::new(embedder)));
+        AsyncDatabase::new(Arc::new(db))
+    }
+
+    /// A runtime with a single blocking thread, so a second call queues
+    /// behind a running one.
+    fn runtime() -> Runtime {
+        Builder::new_current_thread()
+            .max_blocking_threads(1)
+            .enable_time()
+            .build()
+            .unwrap()
+    }
+
+    fn document(id: &str) -> Document {
+        Document {
+            id: id.to_string(),
+            embedding: vec![],
+            text: format!("document {}", id),
+            score: 0.0,
+            metadata: vec![],
+            chunk: None,
+        }
+    }
+
+    #[test]
+    fn times_out_reads_and_writes() {
+        runtime().block_on(async {
+            let db = slow_database(Duration::from_millis(200)).timeout(Duration::from_millis(20));
+            let error = db.query("query".to_string(), 1).await.unwrap_err();
+            assert!(error.starts_with("Request timed out"), "{}", error);
+            let error = db.insert(document("a")).await.unwrap_err();
+            assert!(error.starts_with("Write timed out"), "{}", error);
+        });
+    }
+
+    #[test]
+    fn dropping_a_queued_call_aborts_it() {
+        runtime().block_on(async {
+            let db = slow_database(Duration::from_millis(100));
+            let first = db.clone();
+            let running = tokio::spawn(async move { first.insert(document("a")).await });
+            tokio::time::sleep(Duration::from_millis(20)).await;
+
+            // Queued behind the running insert, then dropped by the timeout.
+            let queued = db.insert(document("b"));
+            assert!(
+                tokio::time::timeout(Duration::from_millis(10), queued)
+                    .await
+                    .is_err()
+            );
+
+            running.await.unwrap().unwrap();
+            tokio::time::sleep(Duration::from_millis(150)).await;
+            assert_eq!(db.count().await.unwrap(), 1);
+            assert!(db.get("b".to_string()).await.is_err());
+        });
+    }
+}
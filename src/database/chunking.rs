@@ -0,0 +1,286 @@
+use crate::database::db::Document;
+use crate::util::word_spans;
+
+/// How long texts are split into separately embedded chunks on ingestion.
+///
+/// Sizes count the tokens of the embedder's tokenizer (`Embedder::token_offsets`),
+/// without the special tokens and document prefix the model adds, so they
+/// should stay a few tokens below its `max_seq_length`. `split` counts
+/// whitespace separated words instead.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum ChunkStrategy {
+    /// Documents are stored whole.
+    #[default]
+    None,
+    /// Fixed windows of `size` tokens, each sharing `overlap` tokens with the
+    /// previous one.
+    TokenWindow { size: usize, overlap: usize },
+    /// Whole sentences packed into chunks of at most `max_tokens` tokens.
+    Sentence { max_tokens: usize },
+    /// Whole paragraphs (separated by blank lines) packed into chunks of at
+    /// most `max_tokens` tokens.
+    Paragraph { max_tokens: usize },
+}
+
+/// A piece of a text, as byte offsets into it.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Chunk {
+    pub start: usize,
+    pub end: usize,
+}
+
+/// Links a chunk document back to the document it was cut from.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ChunkInfo {
+    pub parent_id: String,
+    /// Position of the chunk within the parent, starting at 0.
+    pub index: usize,
+    /// Byte offsets of the chunk text within the parent text.
+    pub start: usize,
+    pub end: usize,
+}
+
+impl ChunkStrategy {
+    pub fn is_enabled(&self) -> bool {
+        *self != ChunkStrategy::None
+    }
+
+    /// Splits `text` into chunks, counting whitespace separated words as
+    /// tokens.
+    pub fn split(&self, text: &str) -> Vec<Chunk> {
+        self.split_tokens(text, &word_spans(text))
+    }
+
+    /// Splits `text` into chunks given the byte ranges of its `tokens`, in
+    /// order. Always returns at least one chunk; units longer than the limit
+    /// fall back to token windows.
+    pub fn split_tokens(&self, text: &str, tokens: &[(usize, usize)]) -> Vec<Chunk> {
+        let whole = (0, text.len());
+        let chunks = match *self {
+            ChunkStrategy::None => vec![],
+            ChunkStrategy::TokenWindow { size, overlap } => {
+                token_windows(tokens, whole, size, overlap)
+            }
+            ChunkStrategy::Sentence { max_tokens } => pack(tokens, &sentences(text), max_tokens),
+            ChunkStrategy::Paragraph { max_tokens } => pack(tokens, &paragraphs(text), max_tokens),
+        };
+        if chunks.is_empty() {
+            return vec![Chunk {
+                start: 0,
+                end: text.len(),
+            }];
+        }
+        chunks
+    }
+
+    /// Splits `document` into one document per chunk, given the byte ranges
+    /// of the tokens of its text, with ids derived from the parent id by
+    /// `chunk_id`. Chunks share the parent's metadata and are embedded
+    /// separately, so a supplied embedding is dropped.
+    pub fn split_document(&self, document: &Document, tokens: &[(usize, usize)]) -> Vec<Document> {
+        self.split_tokens(&document.text, tokens)
+            .into_iter()
+            .enumerate()
+            .map(|(index, chunk)| Document {
+                id: chunk_id(&document.id, index),
+                embedding: vec![],
+                text: document.text[chunk.start..chunk.end].to_string(),
+                score: 0.0,
+                metadata: document.metadata.clone(),
+                chunk: Some(ChunkInfo {
+                    parent_id: document.id.clone(),
+                    index,
+                    start: chunk.start,
+                    end: chunk.end,
+                }),
+            })
+            .collect()
+    }
+}
+
+/// Id of the `index`th chunk of `parent_id`. Such ids may also be taken
+/// by caller documents, so chunks are told apart by their `ChunkInfo`.
+pub fn chunk_id(parent_id: &str, index: usize) -> String {
+    format!("{}#{}", parent_id, index)
+}
+
+/// The `tokens` lying within `range`.
+fn tokens_in(tokens: &[(usize, usize)], range: (usize, usize)) -> &[(usize, usize)] {
+    let first = tokens.partition_point(|&(start, _)| start < range.0);
+    let last = tokens.partition_point(|&(_, end)| end <= range.1);
+    &tokens[first..last.max(first)]
+}
+
+fn token_windows(
+    tokens: &[(usize, usize)],
+    range: (usize, usize),
+    size: usize,
+    overlap: usize,
+) -> Vec<Chunk> {
+    let tokens = tokens_in(tokens, range);
+    let size = size.max(1);
+    let step = size.saturating_sub(overlap).max(1);
+
+    let mut chunks = vec![];
+    let mut first = 0;
+    while first < tokens.len() {
+        let last = (first + size).min(tokens.len()) - 1;
+        chunks.push(Chunk {
+            start: tokens[first].0,
+            end: tokens[last].1,
+        });
+        if last + 1 == tokens.len() {
+            break;
+        }
+        first += step;
+    }
+    chunks
+}
+
+/// Sentences end after `.`, `!` or `?` followed by whitespace.
+fn sentences(text: &str) -> Vec<(usize, usize)> {
+    let mut units = vec![];
+    let mut start = 0;
+    let mut chars = text.char_indices().peekable();
+    while let Some((i, c)) = chars.next() {
+        let at_boundary = matches!(c, '.' | '!' | '?')
+            && chars.peek().is_none_or(|(_, next)| next.is_whitespace());
+        if at_boundary {
+            let end = i + c.len_utf8();
+            units.extend(trimmed(text, (start, end)));
+            start = end;
+        }
+    }
+    units.extend(trimmed(text, (start, text.len())));
+    units
+}
+
+/// Paragraphs are separated by lines containing only whitespace.
+fn paragraphs(text: &str) -> Vec<(usize, usize)> {
+    let mut units = vec![];
+    let mut start = 0;
+    let mut offset = 0;
+    for line in text.split_inclusive('\n') {
+        if line.trim().is_empty() {
+            units.extend(trimmed(text, (start, offset)));
+            start = offset + line.len();
+        }
+        offset += line.len();
+    }
+    units.extend(trimmed(text, (start, text.len())));
+    units
+}
+
+/// `range` without leading and trailing whitespace, or `None` if nothing
+/// else is left.
+fn trimmed(text: &str, range: (usize, usize)) -> Option<(usize, usize)> {
+    let slice = &text[range.0..range.1];
+    let trimmed = slice.trim();
+    if trimmed.is_empty() {
+        return None;
+    }
+    let start = range.0 + (slice.len() - slice.trim_start().len());
+    Some((start, start + trimmed.len()))
+}
+
+/// Greedily packs consecutive `units` into chunks of at most `max_tokens`
+/// tokens.
+fn pack(tokens: &[(usize, usize)], units: &[(usize, usize)], max_tokens: usize) -> Vec<Chunk> {
+    let max_tokens = max_tokens.max(1);
+    let mut chunks = vec![];
+    let mut current: Option<Chunk> = None;
+    let mut count = 0;
+
+    for &unit in units {
+        let unit_tokens = tokens_in(tokens, unit).len();
+        if unit_tokens > max_tokens {
+            chunks.extend(current.take());
+            count = 0;
+            chunks.extend(token_windows(tokens, unit, max_tokens, 0));
+            continue;
+        }
+        if current.is_some() && count + unit_tokens > max_tokens {
+            chunks.extend(current.take());
+            count = 0;
+        }
+        current = Some(match current {
+            Some(chunk) => Chunk {
+                start: chunk.start,
+                end: unit.1,
+            },
+            None => Chunk {
+                start: unit.0,
+                end: unit.1,
+            },
+        });
+        count += unit_tokens;
+    }
+    chunks.extend(current);
+    chunks
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn texts<'a>(text: &'a str, chunks: &[Chunk]) -> Vec<&'a str> {
+        chunks
+            .iter()
+            .map(|chunk| &text[chunk.start..chunk.end])
+            .collect()
+    }
+
+    #[test]
+    fn token_windows_overlap() {
+        let strategy = ChunkStrategy::TokenWindow {
+            size: 3,
+            overlap: 1,
+        };
+        let text = "a b c d e f g";
+        assert_eq!(
+            texts(text, &strategy.split(text)),
+            ["a b c", "c d e", "e f g"]
+        );
+    }
+
+    #[test]
+    fn packs_sentences_and_paragraphs() {
+        let strategy = ChunkStrategy::Sentence { max_tokens: 5 };
+        let text = "One two. Three four five! Six seven eight nine ten eleven.";
+        assert_eq!(
+            texts(text, &strategy.split(text)),
+            [
+                "One two. Three four five!",
+                "Six seven eight nine ten",
+                "eleven."
+            ]
+        );
+
+        let strategy = ChunkStrategy::Paragraph { max_tokens: 4 };
+        let text = "para one here\n\n  para two\n \nthree";
+        assert_eq!(
+            texts(text, &strategy.split(text)),
+            ["para one here", "para two\n \nthree"]
+        );
+    }
+
+    #[test]
+    fn counts_the_given_tokens() {
+        // "unbelievable" as three word pieces.
+        let text = "unbelievable news";
+        let tokens = [(0, 2), (2, 8), (8, 12), (13, 17)];
+        let strategy = ChunkStrategy::TokenWindow {
+            size: 2,
+            overlap: 0,
+        };
+        assert_eq!(
+            texts(text, &strategy.split_tokens(text, &tokens)),
+            ["unbeliev", "able news"]
+        );
+        let strategy = ChunkStrategy::Sentence { max_tokens: 3 };
+        assert_eq!(
+            texts(text, &strategy.split_tokens(text, &tokens)),
+            ["unbelievable", "news"]
+        );
+    }
+}
@@ -1,94 +1,1287 @@
-use crate::database::db::{DatabaseOperations, Document};
-use crate::embeddings::generate_emdedding;
+use crate::database::chunking::chunk_id;
+use crate::database::db::{
+    CollectionConfig, DatabaseOperations, Document, ListOptions, Page, QueryOptions, UpsertOutcome,
+    content_hash,
+};
+use crate::database::explain::Explanation;
+use crate::database::multi_vector::TokenMatrix;
+use crate::database::quantization::{
+    Codes, MIN_CALIBRATION_VECTORS, Quantization, ScalarQuantizer, binarize, hamming_distance,
+};
+use crate::database::reduction::{DimensionReduction, Pca, Reducer};
+use crate::embeddings::{EMBEDDING_BATCH_SIZE, Embedder, Reranker, TokenEmbedding};
+use imbl::Vector;
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::convert::Infallible;
+use std::sync::{Arc, Mutex, RwLock};
+use tch::{Kind, Tensor};
 
+/// Brute-force cosine similarity store.
+///
+/// Readers work on an immutable `Snapshot` that they clone out of the
+/// `RwLock` in O(1), so a query never observes a half-applied write. Writers
+/// are serialised by `writer`, build the next snapshot next to the current
+/// one and publish it with a single pointer swap. Snapshots are made of
+/// persistent collections, so the next one shares everything but the
+/// changed entries with the current one and a write costs O(log n) rather
+/// than a copy of the collection. Embeddings are computed
+/// before any lock is taken, so ingestion does not block search; the
+/// embedder itself is locked for one batch of `EMBEDDING_BATCH_SIZE` texts
+/// at a time, so queries embed in between.
 pub struct CosineDatabase {
-    pub documents: Vec<Document>,
+    config: CollectionConfig,
+    embedder: Mutex<Box<dyn Embedder>>,
+    reranker: Option<Mutex<Box<dyn Reranker>>>,
+    snapshot: RwLock<Arc<Snapshot>>,
+    writer: Mutex<()>,
+    requests: Mutex<RequestLog>,
+}
+
+#[derive(Clone, Default)]
+struct Snapshot {
+    records: Vector<Arc<Record>>,
+    /// Maps each document id to the `seq` of its record.
+    ids: imbl::HashMap<String, u64>,
+    /// Token vectors by record `seq`, kept when late interaction is enabled.
+    tokens: imbl::HashMap<u64, Arc<TokenMatrix>>,
+    reduction: DimensionReduction,
+    /// Applied to every embedding before it is stored or compared. PCA is
+    /// fitted once the collection holds as many vectors as it keeps
+    /// components; until then they are stored in the model's dimension.
+    reducer: Option<Arc<Reducer>>,
+    quantization: Quantization,
+    /// Fitted once an int8 quantized collection holds
+    /// `MIN_CALIBRATION_VECTORS` vectors; until then they are stored and
+    /// scored in full precision.
+    quantizer: Option<Arc<ScalarQuantizer>>,
+    /// Number of vectors `quantizer` was fitted on.
+    calibrated_on: usize,
+    next_seq: u64,
+    /// The normalised embeddings of `records` as one `[records, dimension]`
+    /// matrix, built by the first `query_batch` on this snapshot.
+    corpus: Arc<Mutex<Option<Tensor>>>,
+}
+
+/// A stored document together with its insertion sequence number. Sequence
+/// numbers only ever increase, so `records` stays sorted by `seq` and list
+/// cursors remain valid while new documents are appended. `seq` is also the
+/// compact internal id that index structures refer to instead of the
+/// caller-facing `Document.id`.
+struct Record {
+    seq: u64,
+    /// `content_hash` of `document.text`, used to skip re-embedding on upsert.
+    hash: String,
+    /// `document.embedding` is reduced when the collection reduces
+    /// dimensions. In quantized collections it is also normalised, and
+    /// empty unless full precision vectors are kept.
+    document: Document,
+    /// Quantized embedding in quantized collections.
+    codes: Option<Codes>,
+}
+
+/// A document on its way into the store. `reduced` is set when its
+/// embedding was reused from the stored record, and so is already reduced.
+struct Incoming {
+    document: Document,
+    reduced: bool,
+}
+
+/// Number of client request ids remembered for idempotent upsert retries.
+const REQUEST_LOG_CAPACITY: usize = 10_000;
+
+/// Queries scored together by `query_batch`, which bounds its score matrix
+/// to `QUERY_BLOCK_SIZE` rows whatever the batch size.
+const QUERY_BLOCK_SIZE: usize = 64;
+
+/// Outcomes of recent upserts keyed by client request id, so a retried
+/// request is answered from here instead of being applied twice.
+#[derive(Default)]
+struct RequestLog {
+    outcomes: HashMap<String, Vec<UpsertOutcome>>,
+    order: VecDeque<String>,
+}
+
+impl RequestLog {
+    fn get(&self, request_id: &str) -> Option<Vec<UpsertOutcome>> {
+        self.outcomes.get(request_id).cloned()
+    }
+
+    fn record(&mut self, request_id: String, outcomes: Vec<UpsertOutcome>) {
+        if self.order.len() == REQUEST_LOG_CAPACITY
+            && let Some(oldest) = self.order.pop_front()
+        {
+            self.outcomes.remove(&oldest);
+        }
+        self.order.push_back(request_id.clone());
+        self.outcomes.insert(request_id, outcomes);
+    }
+}
+
+impl Snapshot {
+    fn new(config: &CollectionConfig) -> Snapshot {
+        Snapshot {
+            reduction: config.reduction,
+            reducer: Reducer::untrained(&config.reduction).map(Arc::new),
+            quantization: config.quantization,
+            next_seq: 1,
+            ..Snapshot::default()
+        }
+    }
+
+    /// Appends `document`, whose embedding is already reduced.
+    fn push(&mut self, document: Document) {
+        self.ids.insert(document.id.clone(), self.next_seq);
+        let record = self.record(self.next_seq, document);
+        self.records.push_back(record);
+        self.next_seq += 1;
+    }
+
+    /// Builds the stored form of `document`, whose embedding is already
+    /// reduced, normalising and quantizing it as configured.
+    fn record(&self, seq: u64, mut document: Document) -> Arc<Record> {
+        document.embedding = self.stored_embedding(document.embedding);
+        let codes = match self.quantization {
+            Quantization::None => None,
+            Quantization::Int8 { .. } => self
+                .quantizer
+                .as_ref()
+                .map(|quantizer| Codes::Int8(quantizer.encode(&document.embedding))),
+            Quantization::Binary { .. } => Some(Codes::Binary(binarize(&document.embedding))),
+        };
+        if codes.is_some() && !self.quantization.keeps_full_precision() {
+            document.embedding = vec![];
+        }
+        Arc::new(Record {
+            seq,
+            hash: content_hash(&document.text),
+            document,
+            codes,
+        })
+    }
+
+    /// A reduced `embedding`, normalised in quantized collections.
+    fn stored_embedding(&self, mut embedding: Vec<f64>) -> Vec<f64> {
+        if self.quantization.is_enabled() {
+            normalize(&mut embedding);
+        }
+        embedding
+    }
+
+    /// `embedding`, given in the model's dimension, or the truncated one,
+    /// in the stored dimension.
+    fn reduce(&self, embedding: &[f64]) -> Result<Vec<f64>, String> {
+        match &self.reducer {
+            Some(reducer) => reducer.apply(embedding),
+            None => Ok(embedding.to_vec()),
+        }
+    }
+
+    /// `document` with its embedding reduced.
+    fn reduced(&self, mut document: Document) -> Result<Document, String> {
+        document.embedding = self
+            .reduce(&document.embedding)
+            .map_err(|e| format!("Document {}: {}", document.id, e))?;
+        Ok(document)
+    }
+
+    /// Fits the PCA projection on the stored vectors once there are at least
+    /// as many as it keeps components, and projects them all, so records
+    /// never mix the model's and the reduced dimension.
+    fn fit(&mut self) -> Result<(), String> {
+        let DimensionReduction::Pca { dimension } = self.reduction else {
+            return Ok(());
+        };
+        if self.reducer.is_some() || self.records.len() < dimension.max(1) {
+            return Ok(());
+        }
+        let documents: Vec<Document> = self
+            .records
+            .iter()
+            .map(|record| self.document(record))
+            .collect();
+        let vectors: Vec<&[f64]> = documents
+            .iter()
+            .map(|document| document.embedding.as_slice())
+            .collect();
+        self.reducer = Some(Arc::new(Reducer::Pca(Pca::fit(&vectors, dimension)?)));
+        // The ranges were fitted on unreduced vectors.
+        self.quantizer = None;
+        self.calibrated_on = 0;
+        self.records = self
+            .records
+            .iter()
+            .zip(documents)
+            .map(|(record, document)| Ok(self.record(record.seq, self.reduced(document)?)))
+            .collect::<Result<_, String>>()?;
+        Ok(())
+    }
+
+    /// Replaces the stored vectors with `embeddings`, one per record in the
+    /// model's dimension, and fits the PCA projection again on them.
+    fn refit(&mut self, embeddings: Vec<Vec<f64>>) -> Result<(), String> {
+        self.reducer = None;
+        self.quantizer = None;
+        self.calibrated_on = 0;
+        self.records = self
+            .records
+            .iter()
+            .zip(embeddings)
+            .map(|(record, embedding)| {
+                let document = Document {
+                    embedding,
+                    ..record.document.clone()
+                };
+                self.record(record.seq, document)
+            })
+            .collect();
+        self.fit()
+    }
+
+    /// Fits the int8 quantizer once enough vectors are stored, and again
+    /// whenever the collection has doubled since, so ranges fitted on a
+    /// small collection do not clamp the vectors added later.
+    fn calibrate(&mut self) {
+        if !matches!(self.quantization, Quantization::Int8 { .. }) {
+            return;
+        }
+        let due = match self.quantizer {
+            None => self.records.len() >= MIN_CALIBRATION_VECTORS,
+            Some(_) => self.records.len() >= self.calibrated_on.saturating_mul(2),
+        };
+        if due {
+            self.recalibrate();
+        }
+    }
+
+    /// Fits the quantizer again on every stored vector and re-encodes them.
+    fn recalibrate(&mut self) {
+        if !matches!(self.quantization, Quantization::Int8 { .. }) {
+            return;
+        }
+        let documents: Vec<Document> = self
+            .records
+            .iter()
+            .map(|record| self.document(record))
+            .collect();
+        self.quantizer = ScalarQuantizer::fit(
+            documents
+                .iter()
+                .map(|document| document.embedding.as_slice()),
+            &self.quantization,
+        )
+        .map(Arc::new);
+        self.calibrated_on = documents.len();
+        self.records = self
+            .records
+            .iter()
+            .zip(documents)
+            .map(|(record, document)| self.record(record.seq, document))
+            .collect();
+    }
+
+    /// The embedding of `record`, decoded from its codes when the full
+    /// precision vector was not kept.
+    fn embedding(&self, record: &Record) -> Vec<f64> {
+        match (&self.quantizer, &record.codes) {
+            (Some(quantizer), Some(Codes::Int8(codes))) if record.document.embedding.is_empty() => {
+                quantizer.decode(codes)
+            }
+            _ => record.document.embedding.clone(),
+        }
+    }
+
+    /// The stored document with its embedding filled in.
+    fn document(&self, record: &Record) -> Document {
+        let mut document = record.document.clone();
+        if document.embedding.is_empty() {
+            document.embedding = self.embedding(record);
+        }
+        document
+    }
+
+    /// Whether the reduced `embedding` is what `record` already stores,
+    /// compared on the codes when only those are kept.
+    fn same_embedding(&self, record: &Record, embedding: &[f64]) -> bool {
+        let embedding = self.stored_embedding(embedding.to_vec());
+        match (&self.quantizer, &record.codes) {
+            (Some(quantizer), Some(Codes::Int8(codes))) if record.document.embedding.is_empty() => {
+                quantizer.encode(&embedding) == *codes
+            }
+            _ => record.document.embedding == embedding,
+        }
+    }
+
+    fn remove(&mut self, id: &str) -> Option<Arc<Record>> {
+        let position = self.position(id)?;
+        self.ids.remove(id);
+        let record = self.records.remove(position);
+        self.tokens.remove(&record.seq);
+        Some(record)
+    }
+
+    fn clear(&mut self) {
+        self.records.clear();
+        self.ids.clear();
+        self.tokens.clear();
+        self.reducer = Reducer::untrained(&self.reduction).map(Arc::new);
+        self.quantizer = None;
+        self.calibrated_on = 0;
+    }
+
+    fn set_tokens(&mut self, id: &str, tokens: Arc<TokenMatrix>) {
+        if let Some(&seq) = self.ids.get(id) {
+            self.tokens.insert(seq, tokens);
+        }
+    }
+
+    fn tokens(&self, id: &str) -> Option<&Arc<TokenMatrix>> {
+        self.tokens.get(self.ids.get(id)?)
+    }
+
+    fn find(&self, id: &str) -> Option<&Record> {
+        self.position(id)
+            .map(|position| self.records[position].as_ref())
+    }
+
+    /// Inserts or replaces `document`. Its embedding is reduced unless
+    /// `reduced` says it already is, as when it was reused from the store.
+    fn upsert(&mut self, document: Document, reduced: bool) -> Result<UpsertOutcome, String> {
+        let document = if reduced {
+            document
+        } else {
+            self.reduced(document)?
+        };
+        let Some(position) = self.position(&document.id) else {
+            self.push(document);
+            return Ok(UpsertOutcome::Inserted);
+        };
+        let existing = &self.records[position];
+        if existing.hash == content_hash(&document.text)
+            && existing.document.metadata == document.metadata
+            && self.same_embedding(existing, &document.embedding)
+        {
+            return Ok(UpsertOutcome::Unchanged);
+        }
+        self.records[position] = self.record(existing.seq, document);
+        Ok(UpsertOutcome::Updated)
+    }
+
+    /// Upserts the chunks of `parent_id` and drops chunks left over from a
+    /// longer previous version, reporting one outcome for the whole parent.
+    /// Fails if a chunk id is taken by a document that is not a chunk of
+    /// `parent_id`.
+    fn upsert_chunks(
+        &mut self,
+        parent_id: &str,
+        chunks: Vec<Incoming>,
+    ) -> Result<UpsertOutcome, String> {
+        if let Some(taken) = chunks.iter().map(|chunk| &chunk.document).find(|chunk| {
+            self.find(&chunk.id)
+                .is_some_and(|record| !is_chunk_of(&record.document, parent_id))
+        }) {
+            return Err(format!(
+                "Document {} already exists and is not a chunk of {}",
+                taken.id, parent_id
+            ));
+        }
+        let count = chunks.len();
+        let outcomes: Vec<UpsertOutcome> = chunks
+            .into_iter()
+            .map(|chunk| self.upsert(chunk.document, chunk.reduced))
+            .collect::<Result<_, _>>()?;
+        let removed = self.remove_chunks(parent_id, count);
+        Ok(
+            if removed == 0 && outcomes.iter().all(|o| *o == UpsertOutcome::Inserted) {
+                UpsertOutcome::Inserted
+            } else if removed == 0 && outcomes.iter().all(|o| *o == UpsertOutcome::Unchanged) {
+                UpsertOutcome::Unchanged
+            } else {
+                UpsertOutcome::Updated
+            },
+        )
+    }
+
+    /// Removes the chunks of `parent_id` from index `from` onwards and
+    /// returns how many there were. Documents that merely have a chunk id
+    /// are left alone.
+    fn remove_chunks(&mut self, parent_id: &str, from: usize) -> usize {
+        let mut index = from;
+        loop {
+            let id = chunk_id(parent_id, index);
+            if !self
+                .find(&id)
+                .is_some_and(|record| is_chunk_of(&record.document, parent_id))
+            {
+                break;
+            }
+            self.remove(&id);
+            index += 1;
+        }
+        index - from
+    }
+
+    /// The `corpus` matrix, built on first use.
+    fn corpus(&self) -> Tensor {
+        let mut corpus = self.corpus.lock().unwrap();
+        corpus
+            .get_or_insert_with(|| {
+                normalized_rows(
+                    self.records
+                        .iter()
+                        .map(|record| record.document.embedding.as_slice()),
+                )
+            })
+            .shallow_clone()
+    }
+
+    fn position(&self, id: &str) -> Option<usize> {
+        let seq = *self.ids.get(id)?;
+        self.records
+            .binary_search_by_key(&seq, |record| record.seq)
+            .ok()
+    }
+}
+
+impl CosineDatabase {
+    pub fn new(embedder: Box<dyn Embedder>) -> CosineDatabase {
+        CosineDatabase::with_config(embedder, CollectionConfig::default())
+    }
+
+    pub fn with_config(embedder: Box<dyn Embedder>, config: CollectionConfig) -> CosineDatabase {
+        let snapshot = Snapshot::new(&config);
+        CosineDatabase {
+            config,
+            embedder: Mutex::new(embedder),
+            reranker: None,
+            snapshot: RwLock::new(Arc::new(snapshot)),
+            writer: Mutex::new(()),
+            requests: Mutex::new(RequestLog::default()),
+        }
+    }
+
+    /// Sets the reranker used by `QueryOptions::rerank`.
+    pub fn with_reranker(mut self, reranker: Box<dyn Reranker>) -> CosineDatabase {
+        self.reranker = Some(Mutex::new(reranker));
+        self
+    }
+
+    pub fn config(&self) -> &CollectionConfig {
+        &self.config
+    }
+
+    /// Fits the quantization ranges again on every stored vector now rather
+    /// than when the collection next doubles. Does nothing unless the
+    /// collection is int8 quantized.
+    pub fn recalibrate(&self) -> Result<(), String> {
+        self.write(|snapshot| {
+            snapshot.recalibrate();
+            Ok(())
+        })
+    }
+
+    /// Fits the PCA projection again on the current documents, e.g. after the
+    /// collection grew well beyond the documents it was fitted on. Only the
+    /// projected vectors are stored, so every text is embedded again and
+    /// embeddings supplied by callers are replaced by those of their texts.
+    /// Does nothing unless the collection uses PCA.
+    pub fn refit(&self) -> Result<(), String> {
+        if !matches!(self.config.reduction, DimensionReduction::Pca { .. }) {
+            return Ok(());
+        }
+        self.write(|snapshot| {
+            let texts: Vec<String> = snapshot
+                .records
+                .iter()
+                .map(|record| record.document.text.clone())
+                .collect();
+            snapshot.refit(self.embed_documents(&texts)?)
+        })
+    }
+
+    /// Explains why the stored document `id` matches `query` by aligning
+    /// each query token with its most similar document token.
+    pub fn explain(&self, query: &str, id: &str) -> Result<Explanation, String> {
+        let document = self.get(id)?;
+        let query_text = self.config.query_text(query);
+        let query = self.config.preprocessing.apply(query);
+        let document_text = self.config.document_text(&document.text);
+        let mut tokens = self
+            .embedder
+            .lock()
+            .unwrap()
+            .embed_token_spans(&[query_text.as_str(), document_text.as_str()]);
+        let document_tokens = without_prefix(tokens.pop().unwrap(), &self.config.document_prefix);
+        let query_tokens = without_prefix(tokens.pop().unwrap(), &self.config.query_prefix);
+        Ok(Explanation::new(
+            query,
+            &query_tokens,
+            document,
+            &document_tokens,
+        ))
+    }
+
+    fn embed(&self, text: &str) -> Vec<f64> {
+        self.embedder.lock().unwrap().embed(text)
+    }
+
+    fn embed_batch(&self, texts: &[String]) -> Vec<Vec<f64>> {
+        let texts: Vec<&str> = texts.iter().map(|t| t.as_str()).collect();
+        let Ok(embeddings) = self
+            .in_batches::<_, Infallible>(&texts, |embedder, batch| Ok(embedder.embed_batch(batch)));
+        embeddings
+    }
+
+    /// Runs `embed` over `texts` in batches of `EMBEDDING_BATCH_SIZE`,
+    /// taking the embedder lock for one batch at a time so queries are not
+    /// held up until a large ingestion has been embedded.
+    fn in_batches<T, Error>(
+        &self,
+        texts: &[&str],
+        embed: impl Fn(&dyn Embedder, &[&str]) -> Result<Vec<T>, Error>,
+    ) -> Result<Vec<T>, Error> {
+        let mut results = Vec::with_capacity(texts.len());
+        for batch in texts.chunks(EMBEDDING_BATCH_SIZE) {
+            let embedder = self.embedder.lock().unwrap();
+            results.extend(embed(embedder.as_ref(), batch)?);
+        }
+        Ok(results)
+    }
+
+    /// Embeds a query with the collection's query prefix.
+    fn embed_query(&self, query: &str) -> Vec<f64> {
+        self.embed(&self.config.query_text(query))
+    }
+
+    fn embed_queries(&self, queries: &[String]) -> Vec<Vec<f64>> {
+        let queries: Vec<String> = queries
+            .iter()
+            .map(|query| self.config.query_text(query))
+            .collect();
+        self.embed_batch(&queries)
+    }
+
+    /// Embeds document texts with the collection's document prefix, in
+    /// windows if the collection is configured for it.
+    fn embed_documents(&self, texts: &[String]) -> Result<Vec<Vec<f64>>, String> {
+        let texts: Vec<String> = texts
+            .iter()
+            .map(|text| self.config.document_text(text))
+            .collect();
+        match &self.config.window {
+            Some(window) => {
+                let texts: Vec<&str> = texts.iter().map(|t| t.as_str()).collect();
+                self.in_batches(&texts, |embedder, batch| {
+                    embedder.embed_windowed(batch, window)
+                })
+            }
+            None => Ok(self.embed_batch(&texts)),
+        }
+    }
+
+    /// Embeds `document.text` when the caller did not supply an embedding,
+    /// and checks the supplied one otherwise.
+    fn embedded(&self, mut document: Document) -> Result<Document, String> {
+        if document.embedding.is_empty() {
+            document.embedding = self.embed_documents(&[document.text.clone()])?.remove(0);
+            return Ok(document);
+        }
+        self.check_embedding(&document)?;
+        Ok(document)
+    }
+
+    /// Rejects a supplied embedding with non-finite components or of neither
+    /// the model's nor the truncated dimension. PCA collections only take
+    /// the model's dimension, since their projection is fitted, and
+    /// refitted, on the stored vectors.
+    fn check_embedding(&self, document: &Document) -> Result<(), String> {
+        let dimension = self.embedder.lock().unwrap().dimension();
+        let reduced = match self.config.reduction {
+            DimensionReduction::Truncate { dimension } => Some(dimension),
+            _ => None,
+        };
+        if document.embedding.len() != dimension && Some(document.embedding.len()) != reduced {
+            return Err(format!(
+                "Document {} has a {}-dimensional embedding, expected {}",
+                document.id,
+                document.embedding.len(),
+                dimension
+            ));
+        }
+        if !document.embedding.iter().all(|x| x.is_finite()) {
+            return Err(format!(
+                "Document {} has a non-finite embedding component",
+                document.id
+            ));
+        }
+        Ok(())
+    }
+
+    /// Gives `document` an id from the configured `IdStrategy` if it has none.
+    fn with_id(&self, mut document: Document) -> Result<Document, String> {
+        if document.id.is_empty() {
+            document.id = self.config.id_strategy.generate(&document.text)?;
+        }
+        Ok(document)
+    }
+
+    /// Token vectors for `documents` when late interaction is enabled,
+    /// reusing the stored ones for unchanged texts.
+    fn token_index<'a>(
+        &self,
+        documents: impl IntoIterator<Item = &'a Document>,
+    ) -> Vec<(String, Arc<TokenMatrix>)> {
+        let Some(multi_vector) = &self.config.multi_vector else {
+            return vec![];
+        };
+        let snapshot = self.snapshot();
+        let mut index = vec![];
+        let mut pending = vec![];
+        for document in documents {
+            let stored = snapshot
+                .find(&document.id)
+                .filter(|record| record.hash == content_hash(&document.text))
+                .and_then(|_| snapshot.tokens(&document.id));
+            match stored {
+                Some(tokens) => index.push((document.id.clone(), tokens.clone())),
+                None => pending.push(document),
+            }
+        }
+        drop(snapshot);
+
+        let texts: Vec<String> = pending
+            .iter()
+            .map(|document| self.config.document_text(&document.text))
+            .collect();
+        let texts: Vec<&str> = texts.iter().map(|text| text.as_str()).collect();
+        let Ok(tokens) = self.in_batches::<_, Infallible>(&texts, |embedder, batch| {
+            Ok(embedder.embed_tokens(batch))
+        });
+        for (document, tokens) in pending.into_iter().zip(tokens) {
+            let matrix = TokenMatrix::new(&tokens, multi_vector.dimension);
+            index.push((document.id.clone(), Arc::new(matrix)));
+        }
+        index
+    }
+
+    /// Scores every document matching `metadata` against the query and
+    /// returns them best first. Int8 collections score the codes and
+    /// re-score the top candidates in full precision when configured;
+    /// binary collections only return the `wanted * oversampling` nearest
+    /// by Hamming distance, scored exactly.
+    fn scan(
+        &self,
+        snapshot: &Snapshot,
+        query_embedding: &[f64],
+        metadata: &[String],
+        wanted: usize,
+    ) -> Vec<Document> {
+        // Queries come from the model, so this only fails on an embedder
+        // that breaks its own dimension.
+        let Ok(query_embedding) = &snapshot.reduce(query_embedding) else {
+            return vec![];
+        };
+        let records = snapshot
+            .records
+            .iter()
+            .filter(|record| matches_metadata(&record.document, metadata))
+            .map(|record| record.as_ref());
+
+        let mut scored: Vec<(f64, &Record)> = match snapshot.quantization {
+            Quantization::Binary { oversampling } => {
+                let query_bits = binarize(query_embedding);
+                let mut candidates: Vec<(u32, &Record)> = records
+                    .map(|record| match &record.codes {
+                        Some(Codes::Binary(bits)) => (hamming_distance(&query_bits, bits), record),
+                        _ => (u32::MAX, record),
+                    })
+                    .collect();
+                let count = wanted.saturating_mul(oversampling.max(1));
+                if candidates.len() > count {
+                    candidates.select_nth_unstable_by_key(count, |(distance, _)| *distance);
+                    candidates.truncate(count);
+                }
+                candidates
+                    .into_iter()
+                    .map(|(_, record)| {
+                        let score = cosine_similarity(query_embedding, &record.document.embedding);
+                        (score, record)
+                    })
+                    .collect()
+            }
+            _ => {
+                let quantized_query = snapshot
+                    .quantizer
+                    .as_ref()
+                    .map(|quantizer| quantizer.query(&normalized(query_embedding)));
+                records
+                    .map(|record| {
+                        let score = match (&quantized_query, &record.codes) {
+                            (Some(query), Some(Codes::Int8(codes))) => query.score(codes),
+                            _ => cosine_similarity(query_embedding, &record.document.embedding),
+                        };
+                        (score, record)
+                    })
+                    .collect()
+            }
+        };
+        scored.sort_by(|a, b| b.0.partial_cmp(&a.0).unwrap());
+
+        if snapshot.quantizer.is_some()
+            && let Some(candidates) = self.config.quantization.rescore()
+        {
+            scored.truncate(candidates.max(wanted));
+            for (score, record) in scored.iter_mut() {
+                *score = cosine_similarity(query_embedding, &record.document.embedding);
+            }
+            scored.sort_by(|a, b| b.0.partial_cmp(&a.0).unwrap());
+        }
+
+        scored
+            .into_iter()
+            .map(|(score, record)| {
+                let mut doc = snapshot.document(record);
+                doc.score = score;
+                doc
+            })
+            .collect()
+    }
+
+    /// Candidates the stages after the scan may need for `options`.
+    fn wanted(&self, options: &QueryOptions) -> usize {
+        [
+            (options.offset as usize).saturating_add(options.limit as usize),
+            options.rerank.unwrap_or(0) as usize,
+            self.config
+                .multi_vector
+                .map_or(0, |multi_vector| multi_vector.candidates),
+        ]
+        .into_iter()
+        .max()
+        .unwrap_or(0)
+    }
+
+    /// `scan` for several queries at once. Plain collections score blocks of
+    /// `QUERY_BLOCK_SIZE` queries as one matrix product against the cached
+    /// corpus and keep the top candidates of each; the rest scan per query.
+    fn scan_batch(
+        &self,
+        snapshot: &Snapshot,
+        query_embeddings: &[Vec<f64>],
+        options: &QueryOptions,
+    ) -> Vec<Vec<Document>> {
+        let wanted = self.wanted(options);
+        // The matrix product needs every vector in one dimension.
+        let reduced = query_embeddings
+            .iter()
+            .map(|query_embedding| snapshot.reduce(query_embedding))
+            .collect::<Result<Vec<_>, _>>()
+            .ok()
+            .filter(|reduced| {
+                reduced.first().is_some_and(|first| {
+                    let dimension = first.len();
+                    reduced.iter().all(|query| query.len() == dimension)
+                        && snapshot
+                            .records
+                            .iter()
+                            .all(|record| record.document.embedding.len() == dimension)
+                })
+            });
+        let Some(query_embeddings) = reduced
+            .filter(|_| !self.config.quantization.is_enabled() && options.metadata.is_empty())
+        else {
+            return query_embeddings
+                .iter()
+                .map(|query_embedding| {
+                    self.scan(snapshot, query_embedding, &options.metadata, wanted)
+                })
+                .collect();
+        };
+
+        // Collapsing chunks may need candidates past the top `wanted`.
+        let k = if options.collapse_chunks {
+            snapshot.records.len()
+        } else {
+            wanted.min(snapshot.records.len())
+        };
+        if k == 0 {
+            return vec![vec![]; query_embeddings.len()];
+        }
+        let corpus = snapshot.corpus();
+        let mut results = Vec::with_capacity(query_embeddings.len());
+        for block in query_embeddings.chunks(QUERY_BLOCK_SIZE) {
+            let scores = cosine_similarity_matrix(block, &corpus);
+            let (values, indices) = scores.topk(k as i64, 1, true, true);
+            let values = Vec::<Vec<f64>>::try_from(&values).unwrap();
+            let indices = Vec::<Vec<i64>>::try_from(&indices).unwrap();
+
+            results.extend(
+                values
+                    .iter()
+                    .zip(indices.iter())
+                    .map(|(row_scores, row_indices)| {
+                        row_scores
+                            .iter()
+                            .zip(row_indices.iter())
+                            .map(|(&score, &index)| {
+                                let mut doc = snapshot.document(&snapshot.records[index as usize]);
+                                doc.score = score;
+                                doc
+                            })
+                            .collect()
+                    }),
+            );
+        }
+        results
+    }
+
+    /// Runs the stages after the scan on its ranked documents: late
+    /// interaction re-scoring, chunk collapsing, reranking and paging.
+    fn ranked(
+        &self,
+        snapshot: &Snapshot,
+        query: &str,
+        mut result: Vec<Document>,
+        options: &QueryOptions,
+    ) -> Vec<Document> {
+        if let Some(multi_vector) = &self.config.multi_vector {
+            self.rescore_tokens(
+                query,
+                snapshot,
+                &mut result,
+                multi_vector.candidates,
+                multi_vector.dimension,
+            );
+        }
+        if options.collapse_chunks {
+            let mut parents = HashSet::new();
+            result.retain(|doc| parents.insert(parent_id(doc).to_string()));
+        }
+        if let Some(top_n) = options.rerank {
+            self.rerank(query, &mut result, top_n as usize);
+        }
+        result
+            .into_iter()
+            .skip(options.offset as usize)
+            .take(options.limit as usize)
+            .map(|doc| with_embedding(doc, options.include_embeddings))
+            .collect()
+    }
+
+    /// Re-scores the best `candidates` of the ranked `documents` by MaxSim
+    /// against the query tokens and drops the rest.
+    fn rescore_tokens(
+        &self,
+        query: &str,
+        snapshot: &Snapshot,
+        documents: &mut [Document],
+        candidates: usize,
+        dimension: Option<usize>,
+    ) {
+        let candidates = candidates.min(documents.len());
+        let query = self.config.query_text(query);
+        let query_tokens = self
+            .embedder
+            .lock()
+            .unwrap()
+            .embed_tokens(&[query.as_str()]);
+        let query_tokens = TokenMatrix::new(&query_tokens[0], dimension);
+        let rescored = &mut documents[..candidates];
+        for doc in rescored.iter_mut() {
+            if let Some(tokens) = snapshot.tokens(&doc.id) {
+                doc.score = query_tokens.max_sim(tokens);
+            }
+        }
+        rescored.sort_by(|a, b| b.score.partial_cmp(&a.score).unwrap());
+    }
+
+    /// Keeps the `top_n` best `documents` and re-orders them by the
+    /// reranker's score, which replaces `Document.score`.
+    fn rerank(&self, query: &str, documents: &mut Vec<Document>, top_n: usize) {
+        let Some(reranker) = &self.reranker else {
+            return;
+        };
+        documents.truncate(top_n);
+        let passages: Vec<&str> = documents.iter().map(|doc| doc.text.as_str()).collect();
+        let query = self.config.preprocessing.apply(query);
+        let scores = reranker.lock().unwrap().score(&query, &passages);
+        for (doc, score) in documents.iter_mut().zip(scores) {
+            doc.score = score;
+        }
+        documents.sort_by(|a, b| b.score.partial_cmp(&a.score).unwrap());
+    }
+
+    fn snapshot(&self) -> Arc<Snapshot> {
+        self.snapshot.read().unwrap().clone()
+    }
+
+    /// Applies `change` to a copy of the current snapshot and publishes the
+    /// copy only if `change` succeeds.
+    fn write<T>(
+        &self,
+        change: impl FnOnce(&mut Snapshot) -> Result<T, String>,
+    ) -> Result<T, String> {
+        let _writer = self.writer.lock().map_err(|e| e.to_string())?;
+        let mut next = Snapshot {
+            corpus: Arc::default(),
+            ..Snapshot::clone(&self.snapshot())
+        };
+        let result = change(&mut next)?;
+        next.fit()?;
+        next.calibrate();
+        *self.snapshot.write().map_err(|e| e.to_string())? = Arc::new(next);
+        Ok(result)
+    }
 }
 
 impl DatabaseOperations for CosineDatabase {
-    fn load(&mut self, texts: &Vec<String>) {
-        for text in texts {
-            let embedding = generate_emdedding(text);
-            let document = Document {
-                id: text.clone(),
+    fn load(&self, texts: &Vec<String>) -> Result<(), String> {
+        let documents = texts
+            .iter()
+            .map(|text| Document {
+                id: content_hash(&self.config.preprocessing.apply(text)),
                 text: text.clone(),
-                embedding,
+                embedding: vec![],
                 score: 0.0,
                 metadata: vec![],
-            };
-            self.documents.push(document);
-        }
+                chunk: None,
+            })
+            .collect();
+        self.upsert_batch(documents, None).map(|_| ())
     }
 
     fn query(&self, query: String, n: u32) -> Vec<Document> {
-        let mut result = vec![];
-        let query_embedding = generate_emdedding(&query);
-        for document in &self.documents {
-            let score = cosine_similarity(&query_embedding, &document.embedding);
-            let mut doc = document.clone();
-            doc.score = score;
-            result.push(doc);
+        let options = QueryOptions {
+            limit: n,
+            ..QueryOptions::default()
+        };
+        self.query_with(query, &options)
+    }
+
+    fn query_with(&self, query: String, options: &QueryOptions) -> Vec<Document> {
+        let snapshot = self.snapshot();
+        let query_embedding = self.embed_query(&query);
+        let scanned = self.scan(
+            &snapshot,
+            &query_embedding,
+            &options.metadata,
+            self.wanted(options),
+        );
+        self.ranked(&snapshot, &query, scanned, options)
+    }
+
+    fn query_batch(&self, queries: &[String], n: u32) -> Vec<Vec<Document>> {
+        let snapshot = self.snapshot();
+        let options = QueryOptions {
+            limit: n,
+            ..QueryOptions::default()
+        };
+        let query_embeddings = self.embed_queries(queries);
+        let scanned = self.scan_batch(&snapshot, &query_embeddings, &options);
+        queries
+            .iter()
+            .zip(scanned)
+            .map(|(query, scanned)| self.ranked(&snapshot, query, scanned, &options))
+            .collect()
+    }
+
+    fn list_page(&self, options: &ListOptions) -> Result<Page, String> {
+        let snapshot = self.snapshot();
+        let after = match &options.cursor {
+            Some(cursor) => decode_cursor(cursor)?,
+            None => 0,
+        };
+        let start = match snapshot
+            .records
+            .binary_search_by(|record| record.seq.cmp(&after))
+        {
+            Ok(position) => position + 1,
+            Err(position) => position,
+        };
+
+        let mut documents = vec![];
+        let mut last_seq = None;
+        let tail = snapshot.records.skip(start);
+        let mut remaining = tail
+            .iter()
+            .filter(|record| matches_metadata(&record.document, &options.metadata));
+        for record in remaining.by_ref().take(options.limit) {
+            documents.push(with_embedding(
+                snapshot.document(record),
+                options.include_embeddings,
+            ));
+            last_seq = Some(record.seq);
         }
-        result.sort_by(|a, b| b.score.partial_cmp(&a.score).unwrap());
-        result.drain(..n as usize).collect()
+
+        // An empty page, as with `limit: 0`, continues where it started.
+        let next_cursor = remaining
+            .next()
+            .is_some()
+            .then(|| encode_cursor(last_seq.unwrap_or(after)));
+        Ok(Page {
+            documents,
+            next_cursor,
+        })
     }
 
-    fn insert(&self, _document: Document) -> Result<(), String> {
-        // Implementation here
-        Ok(())
+    fn insert(&self, document: Document) -> Result<(), String> {
+        let document = self.config.preprocessing.document(document);
+        let document = self.embedded(self.with_id(document)?)?;
+        let tokens = self.token_index([&document]);
+        self.write(|snapshot| {
+            if snapshot.position(&document.id).is_some() {
+                return Err(format!("Document {} already exists", document.id));
+            }
+            let document = snapshot.reduced(document)?;
+            snapshot.push(document);
+            for (id, tokens) in tokens {
+                snapshot.set_tokens(&id, tokens);
+            }
+            Ok(())
+        })
     }
 
-    fn update(&self, _document: Document) -> Result<(), String> {
-        // Implementation here
-        Ok(())
+    fn update(&self, document: Document) -> Result<(), String> {
+        let document = self.embedded(self.config.preprocessing.document(document))?;
+        let tokens = self.token_index([&document]);
+        self.write(|snapshot| {
+            let position = snapshot
+                .position(&document.id)
+                .ok_or_else(|| format!("Document {} not found", document.id))?;
+            let seq = snapshot.records[position].seq;
+            let document = snapshot.reduced(document)?;
+            snapshot.records[position] = snapshot.record(seq, document);
+            for (id, tokens) in tokens {
+                snapshot.set_tokens(&id, tokens);
+            }
+            Ok(())
+        })
     }
-    fn delete(&self, _id: &str) -> Result<(), String> {
-        // Implementation here
-        Ok(())
+
+    fn upsert(
+        &self,
+        document: Document,
+        request_id: Option<String>,
+    ) -> Result<UpsertOutcome, String> {
+        self.upsert_batch(vec![document], request_id)
+            .map(|mut outcomes| outcomes.remove(0))
+    }
+
+    fn upsert_batch(
+        &self,
+        documents: Vec<Document>,
+        request_id: Option<String>,
+    ) -> Result<Vec<UpsertOutcome>, String> {
+        if let Some(request_id) = &request_id {
+            let requests = self.requests.lock().map_err(|e| e.to_string())?;
+            if let Some(outcomes) = requests.get(request_id) {
+                return Ok(outcomes);
+            }
+        }
+        let documents = documents
+            .into_iter()
+            .map(|document| self.with_id(self.config.preprocessing.document(document)))
+            .collect::<Result<Vec<_>, _>>()?;
+
+        // Each input becomes one group: the document itself, or its chunks
+        // together with the parent id they belong to.
+        let chunking = self.config.chunking;
+        let incoming = |document| Incoming {
+            document,
+            reduced: false,
+        };
+        let mut groups: Vec<(Option<String>, Vec<Incoming>)> = documents
+            .into_iter()
+            .map(|document| {
+                if chunking.is_enabled() && document.chunk.is_none() {
+                    let tokens = self.embedder.lock().unwrap().token_offsets(&document.text);
+                    let chunks = chunking.split_document(&document, &tokens);
+                    (
+                        Some(document.id),
+                        chunks.into_iter().map(incoming).collect(),
+                    )
+                } else {
+                    (None, vec![incoming(document)])
+                }
+            })
+            .collect();
+
+        // Unchanged texts keep their stored embedding; the rest are embedded
+        // together before taking the writer lock.
+        let snapshot = self.snapshot();
+        let reducer = snapshot.reducer.clone();
+        let mut reused = false;
+        let mut pending = vec![];
+        for Incoming { document, reduced } in groups.iter_mut().flat_map(|(_, documents)| documents)
+        {
+            if !document.embedding.is_empty() {
+                self.check_embedding(document)?;
+                continue;
+            }
+            match snapshot.find(&document.id) {
+                Some(record) if record.hash == content_hash(&document.text) => {
+                    document.embedding = snapshot.embedding(record);
+                    *reduced = true;
+                    reused = true;
+                }
+                _ => pending.push(document),
+            }
+        }
+        drop(snapshot);
+        let texts: Vec<String> = pending
+            .iter()
+            .map(|document| document.text.clone())
+            .collect();
+        for (document, embedding) in pending.into_iter().zip(self.embed_documents(&texts)?) {
+            document.embedding = embedding;
+        }
+        let tokens = self.token_index(
+            groups
+                .iter()
+                .flat_map(|(_, documents)| documents)
+                .map(|incoming| &incoming.document),
+        );
+
+        self.write(|snapshot| {
+            let mut requests = self.requests.lock().map_err(|e| e.to_string())?;
+            if let Some(outcomes) = request_id.as_deref().and_then(|id| requests.get(id)) {
+                return Ok(outcomes);
+            }
+            if reused
+                && matches!(self.config.reduction, DimensionReduction::Pca { .. })
+                && !same_reducer(&snapshot.reducer, &reducer)
+            {
+                return Err("The collection was refitted during the upsert, retry it".to_string());
+            }
+            let outcomes: Vec<UpsertOutcome> = groups
+                .into_iter()
+                .map(|(parent_id, mut documents)| match parent_id {
+                    Some(parent_id) => snapshot.upsert_chunks(&parent_id, documents),
+                    None => {
+                        let Incoming { document, reduced } = documents.remove(0);
+                        snapshot.upsert(document, reduced)
+                    }
+                })
+                .collect::<Result<_, _>>()?;
+            for (id, tokens) in tokens {
+                snapshot.set_tokens(&id, tokens);
+            }
+            if let Some(request_id) = request_id {
+                requests.record(request_id, outcomes.clone());
+            }
+            Ok(outcomes)
+        })
+    }
+
+    fn delete(&self, id: &str) -> Result<(), String> {
+        self.write(|snapshot| {
+            if snapshot.remove(id).is_none() && snapshot.remove_chunks(id, 0) == 0 {
+                return Err(format!("Document {} not found", id));
+            }
+            Ok(())
+        })
     }
 
     fn search(&self, _query: &str) -> Result<Vec<Document>, String> {
         // Implementation here
         Ok(vec![])
     }
+
     fn get(&self, id: &str) -> Result<Document, String> {
-        // Implementation here
-        Ok(Document {
-            id: id.to_string(),
-            text: id.to_string(),
-            embedding: vec![],
-            score: 0.0,
-            metadata: vec![],
-        })
+        let snapshot = self.snapshot();
+        snapshot
+            .find(id)
+            .map(|record| snapshot.document(record))
+            .ok_or_else(|| format!("Document {} not found", id))
     }
 
     fn list(&self) -> Result<Vec<Document>, String> {
-        // Implementation here
-        Ok(vec![])
+        let snapshot = self.snapshot();
+        Ok(snapshot
+            .records
+            .iter()
+            .map(|record| snapshot.document(record))
+            .collect())
     }
 
     fn count(&self) -> Result<usize, String> {
-        // Implementation here
-        Ok(0)
+        Ok(self.snapshot().records.len())
     }
 
     fn clear(&self) -> Result<(), String> {
-        // Implementation here
-        Ok(())
+        self.write(|snapshot| {
+            snapshot.clear();
+            Ok(())
+        })
     }
 
     fn close(&self) -> Result<(), String> {
-        // Implementation here
         Ok(())
     }
 
-    fn get_metadata(&self, _id: &str) -> Result<Vec<String>, String> {
-        // Implementation here
-        Ok(vec![])
+    fn get_metadata(&self, id: &str) -> Result<Vec<String>, String> {
+        self.get(id).map(|document| document.metadata)
+    }
+}
+
+/// Drops the tokens of an embedded `prefix` and shifts the rest to offsets
+/// in the text without it.
+fn without_prefix(tokens: Vec<TokenEmbedding>, prefix: &str) -> Vec<TokenEmbedding> {
+    tokens
+        .into_iter()
+        .filter(|token| token.end > prefix.len())
+        .map(|token| TokenEmbedding {
+            start: token.start.max(prefix.len()) - prefix.len(),
+            end: token.end - prefix.len(),
+            embedding: token.embedding,
+        })
+        .collect()
+}
+
+fn matches_metadata(document: &Document, required: &[String]) -> bool {
+    required
+        .iter()
+        .all(|entry| document.metadata.contains(entry))
+}
+
+/// The id results are collapsed on: the parent id for chunks, the document
+/// id otherwise.
+fn parent_id(document: &Document) -> &str {
+    match &document.chunk {
+        Some(chunk) => &chunk.parent_id,
+        None => &document.id,
+    }
+}
+
+fn is_chunk_of(document: &Document, parent_id: &str) -> bool {
+    document
+        .chunk
+        .as_ref()
+        .is_some_and(|chunk| chunk.parent_id == parent_id)
+}
+
+fn with_embedding(mut document: Document, include_embedding: bool) -> Document {
+    if !include_embedding {
+        document.embedding = vec![];
     }
+    document
+}
+
+/// Cursors are the sequence number of the last document on a page, hex
+/// encoded behind a version prefix so callers treat them as opaque.
+/// Sequence numbers start at 1, so cursor 0 lists from the beginning.
+fn encode_cursor(seq: u64) -> String {
+    format!("c1{:016x}", seq)
 }
 
-fn cosine_similarity(a: &Vec<f64>, b: &Vec<f64>) -> f64 {
+fn decode_cursor(cursor: &str) -> Result<u64, String> {
+    cursor
+        .strip_prefix("c1")
+        .filter(|hex| hex.len() == 16)
+        .and_then(|hex| u64::from_str_radix(hex, 16).ok())
+        .ok_or_else(|| format!("Invalid cursor: {}", cursor))
+}
+
+fn cosine_similarity(a: &[f64], b: &[f64]) -> f64 {
     let norms = norm(a) * norm(b);
     if norms == 0.0 {
         return 0.0;
@@ -100,12 +1293,873 @@ fn cosine_similarity(a: &Vec<f64>, b: &Vec<f64>) -> f64 {
     result
 }
 
-fn dot_product(a: &Vec<f64>, b: &Vec<f64>) -> f64 {
+/// Scores every query against every document as one `[queries, documents]`
+/// matrix product of the L2-normalised embeddings.
+fn cosine_similarity_matrix(queries: &[Vec<f64>], corpus: &Tensor) -> Tensor {
+    let queries = normalized_rows(queries.iter().map(|q| q.as_slice()));
+    queries
+        .matmul(&corpus.tr())
+        .nan_to_num(0.0, None::<f64>, None::<f64>)
+}
+
+fn normalized_rows<'a>(rows: impl ExactSizeIterator<Item = &'a [f64]>) -> Tensor {
+    let row_count = rows.len() as i64;
+    let flat: Vec<f64> = rows.flatten().copied().collect();
+    let matrix = Tensor::from_slice(&flat)
+        .to_kind(Kind::Double)
+        .view([row_count, -1]);
+    let norms = matrix
+        .norm_scalaropt_dim(2, [1], true)
+        .clamp_min(f64::MIN_POSITIVE);
+    matrix / norms
+}
+
+fn same_reducer(a: &Option<Arc<Reducer>>, b: &Option<Arc<Reducer>>) -> bool {
+    match (a, b) {
+        (Some(a), Some(b)) => Arc::ptr_eq(a, b),
+        (None, None) => true,
+        _ => false,
+    }
+}
+
+fn dot_product(a: &[f64], b: &[f64]) -> f64 {
     a.iter()
         .zip(b.iter())
         .fold(0.0, |sum, (&a, &b)| sum + (a * b))
 }
 
-fn norm(a: &Vec<f64>) -> f64 {
+fn norm(a: &[f64]) -> f64 {
     dot_product(a, a).sqrt()
 }
+
+/// Scales `vector` to unit length in place; zero vectors are left as is.
+fn normalize(vector: &mut [f64]) {
+    let norm = vector.iter().map(|x| x * x).sum::<f64>().sqrt();
+    if norm > 0.0 {
+        vector.iter_mut().for_each(|x| *x /= norm);
+    }
+}
+
+fn normalized(vector: &[f64]) -> Vec<f64> {
+    let mut vector = vector.to_vec();
+    normalize(&mut vector);
+    vector
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::database::chunking::ChunkStrategy;
+    use crate::database::db::IdStrategy;
+    use crate::database::multi_vector::MultiVectorConfig;
+    use crate::database::preprocessing::Preprocessing;
+    use crate::database::quantization::Calibration;
+    use crate::embeddings::{HashingEmbedder, SlidingWindow};
+
+    fn database(config: CollectionConfig) -> CosineDatabase {
+        CosineDatabase::with_config(Box::new(HashingEmbedder::new(256)), config)
+    }
+
+    fn document(id: &str, text: &str) -> Document {
+        Document {
+            id: id.to_string(),
+            embedding: vec![],
+            text: text.to_string(),
+            score: 0.0,
+            metadata: vec![],
+            chunk: None,
+        }
+    }
+
+    fn chunked() -> CollectionConfig {
+        CollectionConfig {
+            chunking: ChunkStrategy::TokenWindow {
+                size: 4,
+                overlap: 0,
+            },
+            ..CollectionConfig::default()
+        }
+    }
+
+    #[test]
+    fn embeds_windowed_documents_through_the_embedder() {
+        let db = database(CollectionConfig {
+            window: Some(SlidingWindow::default()),
+            ..CollectionConfig::default()
+        });
+        db.load(&vec![
+            "apples and pears".to_string(),
+            "cars and trucks".to_string(),
+        ])
+        .unwrap();
+        let results = db.query("pears".to_string(), 1);
+        assert_eq!(results[0].text, "apples and pears");
+    }
+
+    #[test]
+    fn upserts_and_removes_chunks_per_parent() {
+        let db = database(chunked());
+        let text = "apples bananas cherries dates eggs figs grapes honey";
+        assert_eq!(
+            db.upsert(document("p", text), None).unwrap(),
+            UpsertOutcome::Inserted
+        );
+        assert_eq!(db.count().unwrap(), 2);
+        assert_eq!(
+            db.upsert(document("p", text), None).unwrap(),
+            UpsertOutcome::Unchanged
+        );
+        assert_eq!(
+            db.upsert(document("p", "apples bananas"), None).unwrap(),
+            UpsertOutcome::Updated
+        );
+        assert_eq!(db.count().unwrap(), 1);
+        db.delete("p").unwrap();
+        assert_eq!(db.count().unwrap(), 0);
+    }
+
+    #[test]
+    fn chunks_leave_caller_documents_with_chunk_ids_alone() {
+        let db = database(chunked());
+        db.insert(document("p#1", "caller document")).unwrap();
+        let text = "apples bananas cherries dates eggs figs grapes honey";
+        assert!(db.upsert(document("p", text), None).is_err());
+        assert_eq!(db.count().unwrap(), 1);
+
+        db.upsert(document("p", "apples bananas"), None).unwrap();
+        db.delete("p").unwrap();
+        assert_eq!(db.get("p#1").unwrap().text, "caller document");
+    }
+
+    fn int8(rescore: Option<usize>) -> CollectionConfig {
+        CollectionConfig {
+            quantization: Quantization::Int8 {
+                calibration: Calibration::PerDimension,
+                rescore,
+            },
+            ..CollectionConfig::default()
+        }
+    }
+
+    fn numbered(range: std::ops::Range<usize>) -> Vec<Document> {
+        range
+            .map(|i| document(&i.to_string(), &format!("document {} word{}", i, i % 7)))
+            .collect()
+    }
+
+    #[test]
+    fn calibrates_int8_once_enough_vectors_are_stored() {
+        let db = database(int8(None));
+        db.insert(document("a", "a single document")).unwrap();
+        assert!(db.snapshot().quantizer.is_none());
+        assert_eq!(db.get("a").unwrap().embedding.len(), 256);
+
+        db.upsert_batch(numbered(0..MIN_CALIBRATION_VECTORS), None)
+            .unwrap();
+        let snapshot = db.snapshot();
+        assert_eq!(snapshot.calibrated_on, MIN_CALIBRATION_VECTORS + 1);
+        assert!(snapshot.records.iter().all(|record| record.codes.is_some()));
+
+        db.upsert_batch(numbered(1000..1000 + MIN_CALIBRATION_VECTORS), None)
+            .unwrap();
+        assert_eq!(db.snapshot().calibrated_on, MIN_CALIBRATION_VECTORS + 1);
+        db.upsert_batch(numbered(2000..2001), None).unwrap();
+        assert_eq!(db.snapshot().calibrated_on, 2 * MIN_CALIBRATION_VECTORS + 2);
+    }
+
+    #[test]
+    fn rescoring_keeps_the_pages_asked_for() {
+        let db = database(int8(Some(2)));
+        db.upsert_batch(numbered(0..MIN_CALIBRATION_VECTORS), None)
+            .unwrap();
+        assert!(db.snapshot().quantizer.is_some());
+        let options = QueryOptions {
+            limit: 5,
+            offset: 5,
+            ..QueryOptions::default()
+        };
+        assert_eq!(db.query_with("document".to_string(), &options).len(), 5);
+    }
+
+    fn pca() -> CollectionConfig {
+        CollectionConfig {
+            reduction: DimensionReduction::Pca { dimension: 4 },
+            ..CollectionConfig::default()
+        }
+    }
+
+    fn dimensions(db: &CosineDatabase) -> Vec<usize> {
+        let snapshot = db.snapshot();
+        let mut dimensions: Vec<usize> = snapshot
+            .records
+            .iter()
+            .map(|record| record.document.embedding.len())
+            .collect();
+        dimensions.dedup();
+        dimensions
+    }
+
+    #[test]
+    fn fits_pca_once_there_are_as_many_vectors_as_components() {
+        let db = database(pca());
+        db.upsert_batch(numbered(0..3), None).unwrap();
+        assert!(db.snapshot().reducer.is_none());
+        assert_eq!(dimensions(&db), [256]);
+
+        db.insert(document("3", "another document")).unwrap();
+        assert!(db.snapshot().reducer.is_some());
+        assert_eq!(dimensions(&db), [4]);
+        let results = db.query_batch(
+            &[
+                "document 1 word1".to_string(),
+                "another document".to_string(),
+            ],
+            2,
+        );
+        assert_eq!(results[0][0].id, "1");
+        assert_eq!(results[1][0].id, "3");
+    }
+
+    #[test]
+    fn pca_only_takes_embeddings_of_the_model_dimension() {
+        let db = database(pca());
+        let mut reduced = document("reduced", "reduced");
+        reduced.embedding = vec![0.5; 4];
+        assert!(db.insert(reduced).is_err());
+        let mut full = document("full", "full");
+        full.embedding = HashingEmbedder::new(256).embed("full");
+        db.insert(full).unwrap();
+    }
+
+    #[test]
+    fn refit_projects_every_document_again() {
+        let db = database(pca());
+        db.upsert_batch(numbered(0..4), None).unwrap();
+        let fitted = db.snapshot().reducer.clone().unwrap();
+        db.upsert_batch(numbered(4..20), None).unwrap();
+        db.refit().unwrap();
+        assert!(!Arc::ptr_eq(
+            db.snapshot().reducer.as_ref().unwrap(),
+            &fitted
+        ));
+        assert_eq!(dimensions(&db), [4]);
+    }
+
+    #[test]
+    fn pca_keeps_reused_embeddings_reduced() {
+        let db = database(pca());
+        db.upsert_batch(numbered(0..4), None).unwrap();
+        let stored = db.get("1").unwrap().embedding;
+        assert_eq!(stored.len(), 4);
+
+        let mut tagged = numbered(1..2).remove(0);
+        tagged.metadata = vec!["tagged".to_string()];
+        assert_eq!(
+            db.upsert_batch(vec![tagged], None).unwrap(),
+            [UpsertOutcome::Updated]
+        );
+        assert_eq!(db.get("1").unwrap().embedding, stored);
+        db.load(&vec!["document 1 word1".to_string()]).unwrap();
+        assert_eq!(dimensions(&db), [4]);
+    }
+
+    #[test]
+    fn late_interaction_keeps_results_past_the_candidates() {
+        let db = database(CollectionConfig {
+            multi_vector: Some(MultiVectorConfig {
+                candidates: 2,
+                dimension: None,
+            }),
+            ..CollectionConfig::default()
+        });
+        db.upsert_batch(numbered(0..10), None).unwrap();
+        let options = QueryOptions {
+            limit: 3,
+            offset: 2,
+            ..QueryOptions::default()
+        };
+        assert_eq!(db.query_with("document".to_string(), &options).len(), 3);
+    }
+
+    #[test]
+    fn query_batch_scores_queries_in_blocks() {
+        let db = database(CollectionConfig::default());
+        db.upsert_batch(numbered(0..10), None).unwrap();
+        let queries: Vec<String> = (0..QUERY_BLOCK_SIZE + 3)
+            .map(|i| format!("document {} word{}", i % 10, i % 10 % 7))
+            .collect();
+        let results = db.query_batch(&queries, 1);
+        assert_eq!(results.len(), queries.len());
+        for (i, documents) in results.iter().enumerate() {
+            assert_eq!(documents[0].id, (i % 10).to_string());
+        }
+    }
+
+    /// Records the size of every batch it embeds.
+    /// Texts of every call into the embedder, by kind of call.
+    #[derive(Default)]
+    struct Calls {
+        batches: Vec<Vec<String>>,
+        windowed: Vec<Vec<String>>,
+    }
+
+    struct RecordingEmbedder {
+        inner: HashingEmbedder,
+        calls: Arc<Mutex<Calls>>,
+    }
+
+    fn recording(config: CollectionConfig) -> (CosineDatabase, Arc<Mutex<Calls>>) {
+        let calls = Arc::new(Mutex::new(Calls::default()));
+        let embedder = RecordingEmbedder {
+            inner: HashingEmbedder::new(256),
+            calls: calls.clone(),
+        };
+        (
+            CosineDatabase::with_config(Box::new(embedder), config),
+            calls,
+        )
+    }
+
+    fn owned(texts: &[&str]) -> Vec<String> {
+        texts.iter().map(|text| text.to_string()).collect()
+    }
+
+    impl Embedder for RecordingEmbedder {
+        fn embed_batch(&self, texts: &[&str]) -> Vec<Vec<f64>> {
+            self.calls.lock().unwrap().batches.push(owned(texts));
+            self.inner.embed_batch(texts)
+        }
+
+        fn embed_windowed(
+            &self,
+            texts: &[&str],
+            window: &SlidingWindow,
+        ) -> Result<Vec<Vec<f64>>, String> {
+            self.calls.lock().unwrap().windowed.push(owned(texts));
+            self.inner.embed_windowed(texts, window)
+        }
+
+        fn dimension(&self) -> usize {
+            self.inner.dimension()
+        }
+
+        fn model_id(&self) -> String {
+            self.inner.model_id()
+        }
+    }
+
+    #[test]
+    fn embeds_in_batches_that_release_the_embedder() {
+        let (db, calls) = recording(CollectionConfig::default());
+        let count = 2 * EMBEDDING_BATCH_SIZE + 3;
+        db.upsert_batch(numbered(0..count), None).unwrap();
+        let sizes: Vec<usize> = calls.lock().unwrap().batches.iter().map(Vec::len).collect();
+        assert_eq!(sizes, [EMBEDDING_BATCH_SIZE, EMBEDDING_BATCH_SIZE, 3]);
+        assert_eq!(db.count().unwrap(), count);
+    }
+
+    #[test]
+    fn concurrent_readers_never_see_partial_writes() {
+        const WRITERS: usize = 4;
+        const BATCHES: usize = 10;
+        const BATCH_SIZE: usize = 8;
+        const SINGLES: usize = 10;
+
+        let db = database(CollectionConfig::default());
+        let done = std::sync::atomic::AtomicUsize::new(0);
+        let batch = |writer: usize, batch: usize, version: usize| -> Vec<Document> {
+            (0..BATCH_SIZE)
+                .map(|i| {
+                    let id = format!("batch-{}-{}-{}", writer, batch, i);
+                    document(&id, &format!("{} version{}", id, version))
+                })
+                .collect()
+        };
+
+        std::thread::scope(|scope| {
+            for writer in 0..WRITERS {
+                let (db, done) = (&db, &done);
+                scope.spawn(move || {
+                    for i in 0..BATCHES {
+                        db.upsert_batch(batch(writer, i, 1), None).unwrap();
+                    }
+                    for i in 0..BATCHES {
+                        db.upsert_batch(batch(writer, i, 2), None).unwrap();
+                    }
+                    for i in 0..SINGLES {
+                        let id = format!("single-{}-{}", writer, i);
+                        db.insert(document(&id, &id)).unwrap();
+                        if i % 2 == 0 {
+                            db.upsert(document(&id, &format!("{} again", id)), None)
+                                .unwrap();
+                        } else {
+                            db.delete(&id).unwrap();
+                        }
+                    }
+                    done.fetch_add(1, std::sync::atomic::Ordering::SeqCst);
+                });
+            }
+            for _ in 0..4 {
+                let (db, done) = (&db, &done);
+                scope.spawn(move || {
+                    while done.load(std::sync::atomic::Ordering::SeqCst) < WRITERS {
+                        let options = ListOptions {
+                            limit: usize::MAX,
+                            ..ListOptions::default()
+                        };
+                        let documents = db.list_page(&options).unwrap().documents;
+                        // Every batch is present with all its documents at
+                        // one version, or not at all.
+                        let mut batches: HashMap<&str, HashSet<&str>> = HashMap::new();
+                        let mut sizes: HashMap<&str, usize> = HashMap::new();
+                        for document in &documents {
+                            if let Some((id, version)) = document.text.split_once(' ')
+                                && id.starts_with("batch-")
+                            {
+                                let batch = &id[..id.rfind('-').unwrap()];
+                                batches.entry(batch).or_default().insert(version);
+                                *sizes.entry(batch).or_default() += 1;
+                            }
+                        }
+                        for (batch, versions) in &batches {
+                            assert_eq!(versions.len(), 1, "{} mixes versions", batch);
+                            assert_eq!(sizes[batch], BATCH_SIZE, "{} is incomplete", batch);
+                        }
+
+                        for document in db.query("version2".to_string(), 5) {
+                            assert_eq!(db.get(&document.id).unwrap().id, document.id);
+                        }
+                    }
+                });
+            }
+        });
+
+        assert_eq!(
+            db.count().unwrap(),
+            WRITERS * (BATCHES * BATCH_SIZE + SINGLES / 2)
+        );
+    }
+
+    #[test]
+    fn query_batch_matches_single_queries() {
+        let db = database(CollectionConfig {
+            multi_vector: Some(MultiVectorConfig {
+                candidates: 3,
+                dimension: None,
+            }),
+            ..chunked()
+        });
+        let texts = [
+            "red apples grow on old trees in the orchard",
+            "green pears ripen slowly on the kitchen table",
+            "fast cars race around the twisting mountain track",
+            "heavy trucks carry apples and pears to market",
+        ];
+        let documents = texts
+            .iter()
+            .enumerate()
+            .map(|(i, text)| document(&i.to_string(), text))
+            .collect();
+        db.upsert_batch(documents, None).unwrap();
+
+        let queries = ["apples and pears".to_string(), "mountain cars".to_string()];
+        let results = db.query_batch(&queries, 4);
+        for (query, batch) in queries.iter().zip(results) {
+            let single = db.query(query.clone(), 4);
+            let ids = |documents: &[Document]| -> Vec<String> {
+                documents
+                    .iter()
+                    .map(|document| document.id.clone())
+                    .collect()
+            };
+            assert_eq!(ids(&batch), ids(&single));
+            for (batch, single) in batch.iter().zip(&single) {
+                assert!((batch.score - single.score).abs() < 1e-9);
+            }
+        }
+    }
+
+    fn ids(documents: &[Document]) -> Vec<&str> {
+        documents
+            .iter()
+            .map(|document| document.id.as_str())
+            .collect()
+    }
+
+    fn page(db: &CosineDatabase, limit: usize, cursor: Option<String>) -> Page {
+        let options = ListOptions {
+            limit,
+            cursor,
+            ..ListOptions::default()
+        };
+        db.list_page(&options).unwrap()
+    }
+
+    #[test]
+    fn cursors_continue_across_inserts_and_deletes() {
+        let db = database(CollectionConfig::default());
+        db.upsert_batch(numbered(0..5), None).unwrap();
+        let first = page(&db, 2, None);
+        assert_eq!(ids(&first.documents), ["0", "1"]);
+
+        db.delete("1").unwrap();
+        db.delete("2").unwrap();
+        db.insert(document("5", "a late document")).unwrap();
+        let second = page(&db, 2, first.next_cursor);
+        assert_eq!(ids(&second.documents), ["3", "4"]);
+        let third = page(&db, 2, second.next_cursor);
+        assert_eq!(ids(&third.documents), ["5"]);
+        assert_eq!(third.next_cursor, None);
+    }
+
+    #[test]
+    fn filtered_pages_skip_other_documents() {
+        let db = database(CollectionConfig::default());
+        let documents = numbered(0..6)
+            .into_iter()
+            .map(|mut document| {
+                if document.id.parse::<usize>().unwrap() % 2 == 0 {
+                    document.metadata = vec!["even".to_string()];
+                }
+                document
+            })
+            .collect();
+        db.upsert_batch(documents, None).unwrap();
+        let options = ListOptions {
+            limit: 2,
+            metadata: vec!["even".to_string()],
+            ..ListOptions::default()
+        };
+        let first = db.list_page(&options).unwrap();
+        assert_eq!(ids(&first.documents), ["0", "2"]);
+        let options = ListOptions {
+            cursor: first.next_cursor,
+            ..options
+        };
+        let second = db.list_page(&options).unwrap();
+        assert_eq!(ids(&second.documents), ["4"]);
+        assert_eq!(second.next_cursor, None);
+    }
+
+    #[test]
+    fn empty_pages_keep_the_cursor() {
+        let db = database(CollectionConfig::default());
+        assert_eq!(page(&db, 0, None).next_cursor, None);
+
+        db.upsert_batch(numbered(0..3), None).unwrap();
+        let empty = page(&db, 0, None);
+        assert!(empty.documents.is_empty());
+        assert_eq!(
+            ids(&page(&db, 10, empty.next_cursor).documents),
+            ["0", "1", "2"]
+        );
+
+        let first = page(&db, 1, None);
+        let empty = page(&db, 0, first.next_cursor.clone());
+        assert_eq!(empty.next_cursor, first.next_cursor);
+    }
+
+    #[test]
+    fn upserts_check_supplied_embeddings() {
+        let db = database(CollectionConfig::default());
+        let mut short = document("short", "short");
+        short.embedding = vec![1.0; 3];
+        let error = db.upsert_batch(vec![short], None).unwrap_err();
+        assert!(error.contains("short"), "{}", error);
+
+        let mut infinite = document("infinite", "infinite");
+        infinite.embedding = vec![0.0; 256];
+        infinite.embedding[7] = f64::NAN;
+        let error = db.upsert(infinite, None).unwrap_err();
+        assert!(error.contains("infinite"), "{}", error);
+        assert_eq!(db.count().unwrap(), 0);
+    }
+
+    #[test]
+    fn id_strategies_fill_in_missing_ids() {
+        let ids_for = |id_strategy: IdStrategy| {
+            let db = database(CollectionConfig {
+                id_strategy,
+                ..CollectionConfig::default()
+            });
+            db.insert(document("", "same text")).unwrap();
+            db.insert(document("", "same text")).unwrap();
+            db.list()
+                .unwrap()
+                .into_iter()
+                .map(|document| document.id)
+                .collect::<Vec<_>>()
+        };
+
+        let v4 = ids_for(IdStrategy::UuidV4);
+        assert_eq!(v4.len(), 2);
+        assert_ne!(v4[0], v4[1]);
+        let v7 = ids_for(IdStrategy::UuidV7);
+        assert_ne!(v7[0], v7[1]);
+        assert!(v7.iter().all(|id| id.as_bytes()[14] == b'7'), "{:?}", v7);
+
+        let db = database(CollectionConfig::default());
+        db.insert(document("", "same text")).unwrap();
+        assert!(db.insert(document("", "same text")).is_err());
+        assert_eq!(
+            ids(&db.list().unwrap()),
+            vec![content_hash("same text").as_str()]
+        );
+
+        let db = database(CollectionConfig {
+            id_strategy: IdStrategy::Caller,
+            ..CollectionConfig::default()
+        });
+        assert!(db.insert(document("", "no id")).is_err());
+        assert!(db.upsert(document("", "no id"), None).is_err());
+        db.insert(document("mine", "with id")).unwrap();
+        assert_eq!(ids(&db.list().unwrap()), vec!["mine"]);
+    }
+
+    #[test]
+    fn metadata_filters_require_every_entry() {
+        let db = database(CollectionConfig::default());
+        let tagged = |id: &str, metadata: &[&str]| Document {
+            metadata: metadata.iter().map(|entry| entry.to_string()).collect(),
+            ..document(id, &format!("fruit {}", id))
+        };
+        db.upsert_batch(
+            vec![
+                tagged("a", &["red", "sweet"]),
+                tagged("b", &["red"]),
+                tagged("c", &["sweet"]),
+                tagged("d", &[]),
+            ],
+            None,
+        )
+        .unwrap();
+
+        let matching = |metadata: &[&str]| {
+            let options = QueryOptions {
+                metadata: metadata.iter().map(|entry| entry.to_string()).collect(),
+                ..QueryOptions::default()
+            };
+            let mut found: Vec<String> = db
+                .query_with("fruit".to_string(), &options)
+                .into_iter()
+                .map(|document| document.id)
+                .collect();
+            found.sort();
+            found
+        };
+        assert_eq!(matching(&[]), vec!["a", "b", "c", "d"]);
+        assert_eq!(matching(&["red"]), vec!["a", "b"]);
+        assert_eq!(matching(&["red", "sweet"]), vec!["a"]);
+        assert!(matching(&["sour"]).is_empty());
+        assert_eq!(db.get_metadata("c").unwrap(), vec!["sweet"]);
+    }
+
+    #[test]
+    fn prefixes_reach_the_embedder_but_not_the_store() {
+        let (db, calls) = recording(CollectionConfig {
+            query_prefix: "query: ".to_string(),
+            document_prefix: "passage: ".to_string(),
+            ..CollectionConfig::default()
+        });
+        db.insert(document("", "apples")).unwrap();
+        db.upsert(document("b", "pears"), None).unwrap();
+        db.query("apples".to_string(), 1);
+        db.query_batch(&owned(&["pears"]), 1);
+
+        assert_eq!(
+            calls.lock().unwrap().batches,
+            [
+                owned(&["passage: apples"]),
+                owned(&["passage: pears"]),
+                owned(&["query: apples"]),
+                owned(&["query: pears"]),
+            ]
+        );
+        let stored = db.get(&content_hash("apples")).unwrap();
+        assert_eq!(stored.text, "apples");
+        assert_eq!(db.get("b").unwrap().text, "pears");
+    }
+
+    #[test]
+    fn windows_documents_but_not_queries() {
+        let (db, calls) = recording(CollectionConfig {
+            document_prefix: "passage: ".to_string(),
+            window: Some(SlidingWindow::default()),
+            ..CollectionConfig::default()
+        });
+        db.upsert_batch(vec![document("a", "apples"), document("b", "pears")], None)
+            .unwrap();
+        db.query("apples".to_string(), 1);
+
+        let calls = calls.lock().unwrap();
+        assert_eq!(
+            calls.windowed,
+            [owned(&["passage: apples", "passage: pears"])]
+        );
+        assert_eq!(calls.batches, [owned(&["apples"])]);
+    }
+
+    /// Scores passages by their length, whatever the query.
+    struct LengthReranker {
+        queries: Arc<Mutex<Vec<String>>>,
+    }
+
+    impl Reranker for LengthReranker {
+        fn score(&self, query: &str, passages: &[&str]) -> Vec<f64> {
+            self.queries.lock().unwrap().push(query.to_string());
+            passages
+                .iter()
+                .map(|passage| passage.len() as f64)
+                .collect()
+        }
+    }
+
+    #[test]
+    fn reranks_the_top_candidates_only() {
+        let texts = ["apple", "apple pie", "apple pie with cream", "car", "truck"];
+        let documents = || texts.iter().map(|text| document(text, text)).collect();
+        let plain = database(CollectionConfig::default());
+        plain.upsert_batch(documents(), None).unwrap();
+        let rerank = QueryOptions {
+            rerank: Some(2),
+            ..QueryOptions::default()
+        };
+        let unranked = plain.query_with("apple".to_string(), &rerank);
+        assert_eq!(unranked.len(), texts.len());
+
+        let queries = Arc::new(Mutex::new(vec![]));
+        let reranked = database(CollectionConfig {
+            preprocessing: Preprocessing {
+                lowercase: true,
+                ..Preprocessing::default()
+            },
+            ..CollectionConfig::default()
+        })
+        .with_reranker(Box::new(LengthReranker {
+            queries: queries.clone(),
+        }));
+        reranked.upsert_batch(documents(), None).unwrap();
+        let results = reranked.query_with("APPLE".to_string(), &rerank);
+
+        let mut top: Vec<&str> = ids(&unranked[..2]);
+        top.sort_by_key(|id| std::cmp::Reverse(id.len()));
+        assert_eq!(ids(&results), top);
+        for result in &results {
+            assert_eq!(result.score, result.text.len() as f64);
+        }
+        assert_eq!(*queries.lock().unwrap(), ["apple"]);
+    }
+
+    fn paged(offset: u32, limit: u32) -> QueryOptions {
+        QueryOptions {
+            offset,
+            limit,
+            ..QueryOptions::default()
+        }
+    }
+
+    #[test]
+    fn offsets_page_through_the_ranking() {
+        let db = database(CollectionConfig::default());
+        db.upsert_batch(numbered(0..10), None).unwrap();
+        let query = || "document word3".to_string();
+        let all = db.query_with(query(), &paged(0, 10));
+        assert_eq!(all.len(), 10);
+
+        let pages: Vec<Document> = [0, 4, 8]
+            .into_iter()
+            .flat_map(|offset| db.query_with(query(), &paged(offset, 4)))
+            .collect();
+        assert_eq!(ids(&pages), ids(&all));
+        assert!(db.query_with(query(), &paged(10, 4)).is_empty());
+        assert!(db.query_with(query(), &paged(1000, 4)).is_empty());
+    }
+
+    #[test]
+    fn huge_offsets_and_limits_do_not_overflow() {
+        let db = database(CollectionConfig::default());
+        db.upsert_batch(numbered(0..5), None).unwrap();
+        let query = || "document".to_string();
+        assert!(
+            db.query_with(query(), &paged(u32::MAX, u32::MAX))
+                .is_empty()
+        );
+        assert!(db.query_with(query(), &paged(u32::MAX, 1)).is_empty());
+        assert_eq!(db.query_with(query(), &paged(0, u32::MAX)).len(), 5);
+        assert_eq!(db.query_with(query(), &paged(3, u32::MAX)).len(), 2);
+    }
+
+    #[test]
+    fn binary_collections_rescore_an_oversampled_shortlist() {
+        let binary = database(CollectionConfig {
+            quantization: Quantization::Binary { oversampling: 2 },
+            ..CollectionConfig::default()
+        });
+        let plain = database(CollectionConfig::default());
+        for db in [&binary, &plain] {
+            db.upsert_batch(numbered(0..20), None).unwrap();
+        }
+        let snapshot = binary.snapshot();
+        assert!(
+            snapshot
+                .records
+                .iter()
+                .all(|record| matches!(record.codes, Some(Codes::Binary(_))))
+        );
+        assert_eq!(binary.get("3").unwrap().embedding.len(), 256);
+
+        let query = binary.embed_query("document 3 word3");
+        assert_eq!(binary.scan(&snapshot, &query, &[], 3).len(), 6);
+
+        let results = binary.query("document 3 word3".to_string(), 3);
+        assert_eq!(results.len(), 3);
+        assert_eq!(results[0].id, "3");
+        for result in &results {
+            let exact = cosine_similarity(&query, &plain.get(&result.id).unwrap().embedding);
+            assert!((result.score - exact).abs() < 1e-9);
+        }
+        assert!(
+            results
+                .windows(2)
+                .all(|pair| pair[0].score >= pair[1].score)
+        );
+    }
+
+    #[test]
+    fn explanation_contributions_add_up_to_the_score() {
+        let db = database(CollectionConfig {
+            preprocessing: Preprocessing {
+                lowercase: true,
+                ..Preprocessing::default()
+            },
+            query_prefix: "query: ".to_string(),
+            document_prefix: "passage: ".to_string(),
+            ..CollectionConfig::default()
+        });
+        db.insert(document("a", "Apples and pears")).unwrap();
+        let explanation = db.explain("Pears or PLUMS", "a").unwrap();
+
+        assert_eq!(explanation.query, "pears or plums");
+        let tokens: Vec<&str> = explanation
+            .query_tokens
+            .iter()
+            .map(|token| token.token.as_str())
+            .collect();
+        assert_eq!(tokens, ["pears", "or", "plums"]);
+        // MaxSim averages the query tokens' best similarities.
+        let share = 1.0 / explanation.query_tokens.len() as f64;
+        let contributions: f64 = explanation
+            .query_tokens
+            .iter()
+            .map(|token| token.score * share)
+            .sum();
+        assert!((contributions - explanation.score).abs() < 1e-9);
+        for alignment in &explanation.alignment {
+            assert_eq!(
+                alignment.similarity,
+                explanation.query_tokens[alignment.query_token].score
+            );
+        }
+        assert!(explanation.score > 0.0 && explanation.score < 1.0);
+    }
+}
@@ -1,5 +1,17 @@
+use crate::database::chunking::{ChunkInfo, ChunkStrategy};
 use crate::database::cosine::CosineDatabase;
-use uuid::Uuid;
+use crate::database::explain::Explanation;
+use crate::database::multi_vector::MultiVectorConfig;
+use crate::database::preprocessing::Preprocessing;
+use crate::database::quantization::Quantization;
+use crate::database::reduction::DimensionReduction;
+use crate::embeddings::{
+    CachedEmbedder, Embedder, EmbeddingConfig, Reranker, SentenceTransformer, SlidingWindow,
+};
+use crate::util::{get_uuid, get_uuid_v7};
+use sha2::{Digest, Sha256};
+use std::io;
+use std::sync::Arc;
 
 #[derive(Debug, Clone)]
 pub struct Document {
@@ -8,83 +20,369 @@ pub struct Document {
     pub text: String,
     pub score: f64,
     pub metadata: Vec<String>,
+    /// Set on documents created by chunking a longer parent document.
+    pub chunk: Option<ChunkInfo>,
+}
+
+/// What an upsert did to the stored document with the same id.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum UpsertOutcome {
+    Inserted,
+    Updated,
+    /// Text, metadata and embedding already matched; nothing was written.
+    Unchanged,
+}
+
+/// Hex encoded SHA-256 of `text`, used to detect unchanged documents.
+pub fn content_hash(text: &str) -> String {
+    format!("{:x}", Sha256::digest(text.as_bytes()))
+}
+
+/// How ids are assigned to documents that arrive without one.
+///
+/// `DatabaseOperations::load` ignores the strategy and always uses
+/// `content_hash`: it has no ids to go by, so random ids would add a copy
+/// of the corpus on every reload.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum IdStrategy {
+    /// Random UUIDv4.
+    UuidV4,
+    /// Time-ordered UUIDv7, so ids sort by insertion time.
+    UuidV7,
+    /// `content_hash` of the text. Identical texts share an id, which makes
+    /// repeated loads of the same corpus idempotent.
+    #[default]
+    ContentHash,
+    /// Callers must set `Document.id` themselves.
+    Caller,
+}
+
+impl IdStrategy {
+    pub fn generate(&self, text: &str) -> Result<String, String> {
+        match self {
+            IdStrategy::UuidV4 => Ok(get_uuid()),
+            IdStrategy::UuidV7 => Ok(get_uuid_v7()),
+            IdStrategy::ContentHash => Ok(content_hash(text)),
+            IdStrategy::Caller => {
+                Err("Document id is required with the Caller id strategy".to_string())
+            }
+        }
+    }
+}
+
+/// Per-collection settings, fixed when the database is created.
+#[derive(Debug, Clone, Default)]
+pub struct CollectionConfig {
+    pub id_strategy: IdStrategy,
+    /// Applied to document texts on every write and to queries before they
+    /// are embedded or reranked.
+    pub preprocessing: Preprocessing,
+    /// Applied by `load`, `upsert` and `upsert_batch`; `insert` and `update`
+    /// store documents exactly as given.
+    pub chunking: ChunkStrategy,
+    /// Prepended to every query before embedding, e.g. `"query: "` for E5
+    /// or a BGE retrieval instruction.
+    pub query_prefix: String,
+    /// Prepended to every document text before embedding, e.g.
+    /// `"passage: "`. Stored texts and content hashes use the text without it.
+    pub document_prefix: String,
+    /// Embeds documents longer than the model's `max_seq_length` as
+    /// overlapping windows instead of truncating them. Queries are always
+    /// truncated.
+    pub window: Option<SlidingWindow>,
+    /// Stores token vectors and re-ranks query candidates by MaxSim.
+    pub multi_vector: Option<MultiVectorConfig>,
+    /// Shrinks embeddings to fewer dimensions before they are stored.
+    pub reduction: DimensionReduction,
+    /// Compression of the stored document embeddings.
+    pub quantization: Quantization,
+}
+
+impl CollectionConfig {
+    /// The text embedded for `query`.
+    pub fn query_text(&self, query: &str) -> String {
+        format!("{}{}", self.query_prefix, self.preprocessing.apply(query))
+    }
+
+    /// The text embedded for a document containing `text`.
+    pub fn document_text(&self, text: &str) -> String {
+        format!("{}{}", self.document_prefix, text)
+    }
+}
+
+/// Options for paging through stored documents with `list_page`.
+#[derive(Debug, Clone)]
+pub struct ListOptions {
+    /// Maximum number of documents returned in one page.
+    pub limit: usize,
+    /// Cursor from a previous `Page::next_cursor`; `None` starts from the beginning.
+    pub cursor: Option<String>,
+    /// Metadata entries that must all be present on a document.
+    pub metadata: Vec<String>,
+    /// When false, returned documents have an empty `embedding`.
+    pub include_embeddings: bool,
+}
+
+impl Default for ListOptions {
+    fn default() -> Self {
+        ListOptions {
+            limit: 100,
+            cursor: None,
+            metadata: vec![],
+            include_embeddings: true,
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct Page {
+    pub documents: Vec<Document>,
+    /// Cursor for the following page, `None` once the listing is exhausted.
+    pub next_cursor: Option<String>,
+}
+
+/// Options for `query_with`. Results are ranked by score, then `offset`
+/// results are skipped and at most `limit` are returned.
+#[derive(Debug, Clone)]
+pub struct QueryOptions {
+    pub limit: u32,
+    pub offset: u32,
+    /// Metadata entries that must all be present on a document.
+    pub metadata: Vec<String>,
+    /// When false, returned documents have an empty `embedding`.
+    pub include_embeddings: bool,
+    /// Return only the best scoring chunk of each parent document.
+    pub collapse_chunks: bool,
+    /// Re-score the top N candidates with the database's `Reranker` and
+    /// order them by that score; candidates below the top N are dropped.
+    /// Ignored when the database has no reranker.
+    pub rerank: Option<u32>,
+}
+
+impl Default for QueryOptions {
+    fn default() -> Self {
+        QueryOptions {
+            limit: 10,
+            offset: 0,
+            metadata: vec![],
+            include_embeddings: true,
+            collapse_chunks: false,
+            rerank: None,
+        }
+    }
 }
 
 pub enum Database {
     CosineDatabase(CosineDatabase),
 }
 
-pub fn new(database_method: &str) -> Database {
+impl Database {
+    /// Sets the reranker used by `QueryOptions::rerank`.
+    pub fn with_reranker(self, reranker: Box<dyn Reranker>) -> Database {
+        match self {
+            Database::CosineDatabase(db) => Database::CosineDatabase(db.with_reranker(reranker)),
+        }
+    }
+
+    /// Fits the quantization ranges again on every stored vector.
+    pub fn recalibrate(&self) -> Result<(), String> {
+        match self {
+            Database::CosineDatabase(db) => db.recalibrate(),
+        }
+    }
+
+    /// Fits the PCA projection again on the current documents.
+    pub fn refit(&self) -> Result<(), String> {
+        match self {
+            Database::CosineDatabase(db) => db.refit(),
+        }
+    }
+
+    /// Token-level explanation of why document `id` matches `query`.
+    pub fn explain(&self, query: &str, id: &str) -> Result<Explanation, String> {
+        match self {
+            Database::CosineDatabase(db) => db.explain(query, id),
+        }
+    }
+}
+
+/// A database handle that can be cloned into and shared between threads.
+/// Every operation takes `&self`; writers never block concurrent readers.
+pub type SharedDatabase = Arc<Database>;
+
+// `SharedDatabase` is only useful if the database can cross threads.
+const _: fn() = || {
+    fn assert_send_sync<T: Send + Sync>() {}
+    assert_send_sync::<Database>();
+};
+
+pub fn new(database_method: &str) -> Result<Database, String> {
+    with_config(database_method, CollectionConfig::default())
+}
+
+/// Creates a database embedding with the model from
+/// `EmbeddingConfig::from_env`, behind a `CachedEmbedder` unless
+/// `cache_capacity` is 0 and no `cache_dir` is set. Fails if the
+/// configuration, the model or the disk cache cannot be loaded.
+pub fn with_config(database_method: &str, config: CollectionConfig) -> Result<Database, String> {
+    let embedding_config = EmbeddingConfig::from_env()?;
+    let model = SentenceTransformer::from_config(&embedding_config).map_err(|e| e.to_string())?;
+    let embedder = cached_embedder(model, &embedding_config).map_err(|e| e.to_string())?;
+    Ok(with_embedder(database_method, embedder, config))
+}
+
+/// `model` behind the cache `embedding_config` asks for, if any.
+pub fn cached_embedder<E: Embedder + 'static>(
+    model: E,
+    embedding_config: &EmbeddingConfig,
+) -> io::Result<Box<dyn Embedder>> {
+    if embedding_config.cache_capacity == 0 && embedding_config.cache_dir.is_none() {
+        return Ok(Box::new(model));
+    }
+    let cache = CachedEmbedder::new(model, embedding_config.cache_capacity);
+    Ok(match &embedding_config.cache_dir {
+        Some(cache_dir) => {
+            let cache = cache.with_disk(cache_dir)?;
+            match embedding_config.cache_disk_limit {
+                Some(limit) => Box::new(cache.with_disk_limit(limit)),
+                None => Box::new(cache),
+            }
+        }
+        None => Box::new(cache),
+    })
+}
+
+pub fn with_embedder(
+    database_method: &str,
+    embedder: Box<dyn Embedder>,
+    config: CollectionConfig,
+) -> Database {
     match database_method {
-        "cosine" => Database::CosineDatabase(CosineDatabase { documents: vec![] }),
+        "cosine" => Database::CosineDatabase(CosineDatabase::with_config(embedder, config)),
         _ => panic!("Unsupported database method"),
     }
 }
 
+pub fn shared(database_method: &str) -> Result<SharedDatabase, String> {
+    new(database_method).map(Arc::new)
+}
+
 pub trait DatabaseOperations {
+    /// Adds a new document. An empty `id` is filled in by the collection's
+    /// `IdStrategy`.
     fn insert(&self, document: Document) -> Result<(), String>;
     fn update(&self, document: Document) -> Result<(), String>;
+    /// Inserts `document` or replaces the stored document with the same id.
+    /// The text is only embedded if it changed and no embedding is supplied.
+    /// Retrying with the same `request_id` returns the first outcome without
+    /// applying the write again.
+    fn upsert(
+        &self,
+        document: Document,
+        request_id: Option<String>,
+    ) -> Result<UpsertOutcome, String>;
+    fn upsert_batch(
+        &self,
+        documents: Vec<Document>,
+        request_id: Option<String>,
+    ) -> Result<Vec<UpsertOutcome>, String>;
+    /// Deletes a document, or every chunk of a chunked parent document.
     fn delete(&self, id: &str) -> Result<(), String>;
     fn search(&self, query: &str) -> Result<Vec<Document>, String>;
     fn get(&self, id: &str) -> Result<Document, String>;
     fn list(&self) -> Result<Vec<Document>, String>;
+    fn list_page(&self, options: &ListOptions) -> Result<Page, String>;
     fn count(&self) -> Result<usize, String>;
     fn clear(&self) -> Result<(), String>;
     fn close(&self) -> Result<(), String>;
     fn get_metadata(&self, id: &str) -> Result<Vec<String>, String>;
-    fn load(&mut self, texts: &Vec<String>);
+    /// Upserts `texts` under their `content_hash`, whatever the collection's
+    /// `IdStrategy`, so loading the same corpus again changes nothing.
+    /// Fails, without storing anything, where `upsert_batch` would.
+    fn load(&self, texts: &Vec<String>) -> Result<(), String>;
     fn query(&self, query: String, n: u32) -> Vec<Document>;
+    fn query_with(&self, query: String, options: &QueryOptions) -> Vec<Document>;
+    /// The results of `query` for each of `queries`, embedded and scored
+    /// together.
+    fn query_batch(&self, queries: &[String], n: u32) -> Vec<Vec<Document>>;
 }
 
 impl DatabaseOperations for Database {
-    fn insert(&self, _document: Document) -> Result<(), String> {
-        // Implementation here
-        Ok(())
+    fn insert(&self, document: Document) -> Result<(), String> {
+        match self {
+            Database::CosineDatabase(db) => db.insert(document),
+        }
     }
-    fn update(&self, _document: Document) -> Result<(), String> {
-        // Implementation here
-        Ok(())
+    fn update(&self, document: Document) -> Result<(), String> {
+        match self {
+            Database::CosineDatabase(db) => db.update(document),
+        }
     }
-    fn delete(&self, _id: &str) -> Result<(), String> {
-        // Implementation here
-        Ok(())
+    fn upsert(
+        &self,
+        document: Document,
+        request_id: Option<String>,
+    ) -> Result<UpsertOutcome, String> {
+        match self {
+            Database::CosineDatabase(db) => db.upsert(document, request_id),
+        }
     }
-    fn search(&self, _query: &str) -> Result<Vec<Document>, String> {
-        // Implementation here
-        Ok(vec![])
+    fn upsert_batch(
+        &self,
+        documents: Vec<Document>,
+        request_id: Option<String>,
+    ) -> Result<Vec<UpsertOutcome>, String> {
+        match self {
+            Database::CosineDatabase(db) => db.upsert_batch(documents, request_id),
+        }
+    }
+    fn delete(&self, id: &str) -> Result<(), String> {
+        match self {
+            Database::CosineDatabase(db) => db.delete(id),
+        }
+    }
+    fn search(&self, query: &str) -> Result<Vec<Document>, String> {
+        match self {
+            Database::CosineDatabase(db) => db.search(query),
+        }
     }
     fn get(&self, id: &str) -> Result<Document, String> {
-        // Implementation here
-        Ok(Document {
-            id: id.to_string(),
-            text: id.to_string(),
-            embedding: vec![],
-            score: 0.0,
-            metadata: vec![],
-        })
+        match self {
+            Database::CosineDatabase(db) => db.get(id),
+        }
     }
     fn list(&self) -> Result<Vec<Document>, String> {
-        // Implementation here
-        Ok(vec![])
+        match self {
+            Database::CosineDatabase(db) => db.list(),
+        }
+    }
+    fn list_page(&self, options: &ListOptions) -> Result<Page, String> {
+        match self {
+            Database::CosineDatabase(db) => db.list_page(options),
+        }
     }
     fn count(&self) -> Result<usize, String> {
-        // Implementation here
-        Ok(0)
+        match self {
+            Database::CosineDatabase(db) => db.count(),
+        }
     }
     fn clear(&self) -> Result<(), String> {
-        // Implementation here
-        Ok(())
+        match self {
+            Database::CosineDatabase(db) => db.clear(),
+        }
     }
     fn close(&self) -> Result<(), String> {
-        // Implementation here
-        Ok(())
+        match self {
+            Database::CosineDatabase(db) => db.close(),
+        }
     }
-    fn get_metadata(&self, _id: &str) -> Result<Vec<String>, String> {
-        // Implementation here
-        Ok(vec![])
+    fn get_metadata(&self, id: &str) -> Result<Vec<String>, String> {
+        match self {
+            Database::CosineDatabase(db) => db.get_metadata(id),
+        }
     }
 
-    fn load(&mut self, texts: &Vec<String>) {
+    fn load(&self, texts: &Vec<String>) -> Result<(), String> {
         match self {
             Database::CosineDatabase(db) => db.load(texts),
         }
@@ -95,4 +393,16 @@ impl DatabaseOperations for Database {
             Database::CosineDatabase(db) => db.query(query, n),
         }
     }
+
+    fn query_with(&self, query: String, options: &QueryOptions) -> Vec<Document> {
+        match self {
+            Database::CosineDatabase(db) => db.query_with(query, options),
+        }
+    }
+
+    fn query_batch(&self, queries: &[String], n: u32) -> Vec<Vec<Document>> {
+        match self {
+            Database::CosineDatabase(db) => db.query_batch(queries, n),
+        }
+    }
 }
@@ -0,0 +1,162 @@
+use crate::database::db::Document;
+use crate::embeddings::TokenEmbedding;
+
+/// A token of the query or of `Document.text`.
+#[derive(Debug, Clone)]
+pub struct TokenScore {
+    /// The part of the text the token came from.
+    pub token: String,
+    /// Byte offsets of the token within its text.
+    pub start: usize,
+    pub end: usize,
+    /// Cosine similarity to the most similar token on the other side.
+    pub score: f64,
+}
+
+/// A query token and the document token most similar to it, as indices
+/// into `Explanation::query_tokens` and `Explanation::document_tokens`.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Alignment {
+    pub query_token: usize,
+    pub document_token: usize,
+    pub similarity: f64,
+}
+
+/// A range of `Document.text` to highlight, as byte offsets.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Span {
+    pub start: usize,
+    pub end: usize,
+}
+
+/// Why a document matched a query, from the similarity of their token
+/// vectors.
+#[derive(Debug, Clone)]
+pub struct Explanation {
+    /// The query after preprocessing; query token offsets refer to it.
+    pub query: String,
+    pub document: Document,
+    /// MaxSim: the mean over query tokens of their best similarity, on the
+    /// scale of cosine similarity.
+    pub score: f64,
+    pub query_tokens: Vec<TokenScore>,
+    pub document_tokens: Vec<TokenScore>,
+    /// The best document token for each query token.
+    pub alignment: Vec<Alignment>,
+}
+
+impl Explanation {
+    pub fn new(
+        query: String,
+        query_tokens: &[TokenEmbedding],
+        document: Document,
+        document_tokens: &[TokenEmbedding],
+    ) -> Explanation {
+        let query_vectors: Vec<Vec<f64>> = query_tokens
+            .iter()
+            .map(|token| normalized(&token.embedding))
+            .collect();
+        let document_vectors: Vec<Vec<f64>> = document_tokens
+            .iter()
+            .map(|token| normalized(&token.embedding))
+            .collect();
+
+        let unmatched = if query_vectors.is_empty() {
+            0.0
+        } else {
+            f64::MIN
+        };
+        let mut document_scores = vec![unmatched; document_tokens.len()];
+        let mut alignment = vec![];
+        let mut query_scores = vec![];
+        for (query_token, query_vector) in query_vectors.iter().enumerate() {
+            let mut best: Option<(usize, f64)> = None;
+            for (document_token, document_vector) in document_vectors.iter().enumerate() {
+                let similarity = dot(query_vector, document_vector);
+                let document_score = &mut document_scores[document_token];
+                *document_score = document_score.max(similarity);
+                if best.is_none_or(|(_, best_similarity)| similarity > best_similarity) {
+                    best = Some((document_token, similarity));
+                }
+            }
+            let (document_token, similarity) = best.unwrap_or((0, 0.0));
+            query_scores.push(similarity);
+            if best.is_some() {
+                alignment.push(Alignment {
+                    query_token,
+                    document_token,
+                    similarity,
+                });
+            }
+        }
+
+        let score = if query_scores.is_empty() {
+            0.0
+        } else {
+            query_scores.iter().sum::<f64>() / query_scores.len() as f64
+        };
+        Explanation {
+            query_tokens: token_scores(&query, query_tokens, &query_scores),
+            document_tokens: token_scores(&document.text, document_tokens, &document_scores),
+            query,
+            document,
+            score,
+            alignment,
+        }
+    }
+
+    /// Ranges of `document.text` covered by document tokens scoring at
+    /// least `min_score`. Tokens separated by nothing but whitespace, such
+    /// as the word pieces of one word, are merged into one span.
+    pub fn highlights(&self, min_score: f64) -> Vec<Span> {
+        let text = &self.document.text;
+        let mut spans: Vec<Span> = vec![];
+        for token in self
+            .document_tokens
+            .iter()
+            .filter(|token| token.score >= min_score)
+        {
+            match spans.last_mut() {
+                Some(last)
+                    if token.start <= last.end
+                        || text
+                            .get(last.end..token.start)
+                            .is_some_and(|gap| gap.trim().is_empty()) =>
+                {
+                    last.end = last.end.max(token.end);
+                }
+                _ => spans.push(Span {
+                    start: token.start,
+                    end: token.end,
+                }),
+            }
+        }
+        spans
+    }
+}
+
+fn token_scores(text: &str, tokens: &[TokenEmbedding], scores: &[f64]) -> Vec<TokenScore> {
+    tokens
+        .iter()
+        .zip(scores)
+        .map(|(token, &score)| TokenScore {
+            token: text.get(token.start..token.end).unwrap_or("").to_string(),
+            start: token.start,
+            end: token.end,
+            score,
+        })
+        .collect()
+}
+
+fn normalized(vector: &[f64]) -> Vec<f64> {
+    let norm = vector.iter().map(|x| x * x).sum::<f64>().sqrt();
+    if norm > 0.0 {
+        vector.iter().map(|x| x / norm).collect()
+    } else {
+        vector.to_vec()
+    }
+}
+
+fn dot(a: &[f64], b: &[f64]) -> f64 {
+    a.iter().zip(b).map(|(a, b)| a * b).sum()
+}
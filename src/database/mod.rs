@@ -1,3 +1,20 @@
+pub mod async_db;
+pub mod chunking;
 pub mod cosine;
 pub mod db;
-pub use db::{DatabaseOperations, new};
+pub mod explain;
+pub mod multi_vector;
+pub mod preprocessing;
+pub mod quantization;
+pub mod reduction;
+pub use async_db::AsyncDatabase;
+pub use chunking::{ChunkInfo, ChunkStrategy};
+pub use db::{
+    CollectionConfig, DatabaseOperations, IdStrategy, ListOptions, Page, QueryOptions,
+    SharedDatabase, UpsertOutcome, cached_embedder, new, shared, with_config, with_embedder,
+};
+pub use explain::{Alignment, Explanation, Span, TokenScore};
+pub use multi_vector::MultiVectorConfig;
+pub use preprocessing::Preprocessing;
+pub use quantization::{Calibration, Quantization};
+pub use reduction::DimensionReduction;
@@ -0,0 +1,92 @@
+/// Late-interaction (ColBERT-style) scoring settings for a collection.
+///
+/// Every document also stores one vector per token. Queries first rank
+/// documents by their single embedding, then re-score the best `candidates`
+/// by MaxSim between query and document tokens, which keeps the cost of the
+/// token comparison bounded on large collections. Results past the
+/// candidates follow them in their single-vector order and score.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct MultiVectorConfig {
+    /// Number of single-vector results re-scored with MaxSim.
+    pub candidates: usize,
+    /// Keep only the leading `dimension` components of each token vector,
+    /// re-normalised, to shrink storage. The vectors are truncated, not
+    /// projected, so this only suits models whose leading components carry
+    /// most of the information, such as Matryoshka-trained ones. `None`
+    /// keeps the full vectors.
+    pub dimension: Option<usize>,
+}
+
+impl Default for MultiVectorConfig {
+    fn default() -> Self {
+        MultiVectorConfig {
+            candidates: 100,
+            dimension: None,
+        }
+    }
+}
+
+/// The token vectors of one text: L2-normalised rows stored as `f32`, at
+/// half the size of the `f64` document embedding.
+#[derive(Debug, Clone)]
+pub struct TokenMatrix {
+    dimension: usize,
+    values: Vec<f32>,
+}
+
+impl TokenMatrix {
+    /// Keeps the leading `dimension` components of each token, see
+    /// `MultiVectorConfig::dimension`.
+    pub fn new(tokens: &[Vec<f64>], dimension: Option<usize>) -> TokenMatrix {
+        let full_dimension = tokens.first().map_or(0, |token| token.len());
+        let dimension = dimension.map_or(full_dimension, |d| d.min(full_dimension));
+
+        let mut values = Vec::with_capacity(tokens.len() * dimension);
+        for token in tokens {
+            let token = &token[..dimension];
+            let norm = token.iter().map(|x| x * x).sum::<f64>().sqrt();
+            let scale = if norm > 0.0 { 1.0 / norm } else { 0.0 };
+            values.extend(token.iter().map(|x| (x * scale) as f32));
+        }
+        TokenMatrix { dimension, values }
+    }
+
+    /// Number of token vectors.
+    pub fn len(&self) -> usize {
+        if self.dimension == 0 {
+            return 0;
+        }
+        self.values.len() / self.dimension
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    fn rows(&self) -> impl Iterator<Item = &[f32]> {
+        self.values.chunks_exact(self.dimension.max(1))
+    }
+
+    /// MaxSim with `self` as the query: each query token is matched with its
+    /// most similar document token. The matches are averaged rather than
+    /// summed, so scores stay in `[-1, 1]` like cosine similarity.
+    pub fn max_sim(&self, document: &TokenMatrix) -> f64 {
+        if self.is_empty() || document.is_empty() || self.dimension != document.dimension {
+            return 0.0;
+        }
+        let total: f64 = self
+            .rows()
+            .map(|query_token| {
+                document
+                    .rows()
+                    .map(|document_token| dot(query_token, document_token))
+                    .fold(f32::MIN, f32::max) as f64
+            })
+            .sum();
+        total / self.len() as f64
+    }
+}
+
+fn dot(a: &[f32], b: &[f32]) -> f32 {
+    a.iter().zip(b).map(|(x, y)| x * y).sum()
+}
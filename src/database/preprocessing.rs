@@ -0,0 +1,364 @@
+use crate::database::db::Document;
+use unicode_normalization::UnicodeNormalization;
+
+/// Cleanup applied to document texts on ingestion and to queries before
+/// they are embedded, so both sides see text in the same form.
+///
+/// Stored documents keep the preprocessed text, which is also what content
+/// hashes, content based ids and chunk offsets refer to, so every enabled
+/// step changes what callers get back. Every step is off by default.
+/// `Preprocessing::standard` enables the ones that only change how text is
+/// written; `strip_html` and `strip_quotes` remove content and have to be
+/// enabled explicitly for sources that need them.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct Preprocessing {
+    /// Removes HTML tags, along with the contents of `script` and `style`
+    /// elements, and decodes character references.
+    pub strip_html: bool,
+    /// Unicode NFKC normalisation: folds compatibility characters such as
+    /// ligatures, full-width forms and non-breaking spaces.
+    pub unicode_normalization: bool,
+    /// Drops control characters other than tabs and line breaks.
+    pub remove_control_characters: bool,
+    /// Trims the text and replaces every run of whitespace with one space.
+    pub collapse_whitespace: bool,
+    /// Removes quotes wrapping the whole text, like the ones
+    /// `AnyValue::to_string` puts around polars strings.
+    pub strip_quotes: bool,
+    /// Only useful for cased models; uncased tokenizers lowercase anyway.
+    pub lowercase: bool,
+    /// Tags documents with a `lang=<code>` metadata entry from
+    /// `detect_language`, unless they already carry a `lang=` entry.
+    pub detect_language: bool,
+}
+
+impl Preprocessing {
+    /// Unicode normalisation, control character removal and whitespace
+    /// collapsing.
+    pub fn standard() -> Preprocessing {
+        Preprocessing {
+            strip_html: false,
+            unicode_normalization: true,
+            remove_control_characters: true,
+            collapse_whitespace: true,
+            strip_quotes: false,
+            lowercase: false,
+            detect_language: false,
+        }
+    }
+
+    pub fn is_enabled(&self) -> bool {
+        *self != Preprocessing::default()
+    }
+
+    /// Runs the enabled steps on `text`, in field order.
+    pub fn apply(&self, text: &str) -> String {
+        let mut text = text.to_string();
+        if self.strip_html {
+            text = strip_html(&text);
+        }
+        if self.unicode_normalization {
+            text = text.nfkc().collect();
+        }
+        if self.remove_control_characters {
+            text.retain(|c| !c.is_control() || matches!(c, '\t' | '\n' | '\r'));
+        }
+        if self.collapse_whitespace {
+            text = text.split_whitespace().collect::<Vec<_>>().join(" ");
+        }
+        if self.strip_quotes {
+            text = strip_quotes(&text).to_string();
+        }
+        if self.lowercase {
+            text = text.to_lowercase();
+        }
+        text
+    }
+
+    /// Preprocesses `document.text` and adds the language tag if enabled.
+    pub fn document(&self, mut document: Document) -> Document {
+        if !self.is_enabled() {
+            return document;
+        }
+        document.text = self.apply(&document.text);
+        if self.detect_language
+            && !document
+                .metadata
+                .iter()
+                .any(|entry| entry.starts_with("lang="))
+            && let Some(language) = detect_language(&document.text)
+        {
+            document.metadata.push(format!("lang={}", language));
+        }
+        document
+    }
+}
+
+/// Replaces tags with spaces, so words on either side of a block element
+/// stay apart, and decodes character references.
+fn strip_html(html: &str) -> String {
+    let mut text = String::with_capacity(html.len());
+    let mut rest = html;
+    while let Some(start) = rest.find('<') {
+        text.push_str(&decode_entities(&rest[..start]));
+        let tag = &rest[start..];
+        // A `<` not followed by a tag name, `/` or `!` is text.
+        let opens_tag = tag[1..]
+            .chars()
+            .next()
+            .is_some_and(|c| c.is_ascii_alphabetic() || c == '/' || c == '!');
+        let Some(end) = tag.find('>').filter(|_| opens_tag) else {
+            text.push('<');
+            rest = &tag[1..];
+            continue;
+        };
+        text.push(' ');
+        rest = &tag[end + 1..];
+
+        let name: String = tag[1..end]
+            .chars()
+            .take_while(|c| c.is_ascii_alphanumeric())
+            .collect::<String>()
+            .to_ascii_lowercase();
+        if name == "script" || name == "style" {
+            let close = format!("</{}", name);
+            rest = match rest.to_ascii_lowercase().find(&close) {
+                Some(position) => {
+                    let after = &rest[position..];
+                    after.find('>').map_or("", |end| &after[end + 1..])
+                }
+                None => "",
+            };
+        }
+    }
+    text.push_str(&decode_entities(rest));
+    text
+}
+
+/// Decodes numeric character references and the common named ones; other
+/// references are kept as written.
+fn decode_entities(text: &str) -> String {
+    let mut decoded = String::with_capacity(text.len());
+    let mut rest = text;
+    while let Some(start) = rest.find('&') {
+        decoded.push_str(&rest[..start]);
+        let reference = &rest[start + 1..];
+        let character = reference
+            .find(';')
+            .filter(|&end| end <= 10)
+            .and_then(|end| Some((entity(&reference[..end])?, end)));
+        match character {
+            Some((character, end)) => {
+                decoded.push(character);
+                rest = &reference[end + 1..];
+            }
+            None => {
+                decoded.push('&');
+                rest = reference;
+            }
+        }
+    }
+    decoded.push_str(rest);
+    decoded
+}
+
+fn entity(name: &str) -> Option<char> {
+    match name {
+        "amp" => Some('&'),
+        "lt" => Some('<'),
+        "gt" => Some('>'),
+        "quot" => Some('"'),
+        "apos" => Some('\''),
+        "nbsp" => Some('\u{a0}'),
+        _ => {
+            let code = match name.strip_prefix("#x").or_else(|| name.strip_prefix("#X")) {
+                Some(hex) => u32::from_str_radix(hex, 16).ok()?,
+                None => name.strip_prefix('#')?.parse().ok()?,
+            };
+            char::from_u32(code)
+        }
+    }
+}
+
+/// `text` without surrounding whitespace and matching pairs of quotes
+/// around all of it.
+fn strip_quotes(mut text: &str) -> &str {
+    const QUOTES: [(char, char); 6] = [
+        ('"', '"'),
+        ('\'', '\''),
+        ('`', '`'),
+        ('\u{201c}', '\u{201d}'),
+        ('\u{2018}', '\u{2019}'),
+        ('\u{ab}', '\u{bb}'),
+    ];
+    loop {
+        text = text.trim();
+        let stripped = QUOTES.iter().find_map(|&(open, close)| {
+            text.strip_prefix(open)
+                .and_then(|inner| inner.strip_suffix(close))
+        });
+        match stripped {
+            Some(inner) => text = inner,
+            None => return text,
+        }
+    }
+}
+
+/// Function words that are frequent in one Latin-script language.
+const STOPWORDS: [(&str, &[&str]); 7] = [
+    (
+        "en",
+        &[
+            "the", "and", "of", "to", "is", "that", "with", "for", "are", "this", "was", "you",
+            "what", "how",
+        ],
+    ),
+    (
+        "de",
+        &[
+            "der", "die", "und", "das", "ist", "nicht", "ein", "eine", "mit", "den", "von", "ich",
+            "auf", "wie",
+        ],
+    ),
+    (
+        "fr",
+        &[
+            "le", "les", "et", "est", "une", "des", "du", "pas", "pour", "dans", "qui", "sur",
+            "au", "avec",
+        ],
+    ),
+    (
+        "es",
+        &[
+            "el", "los", "las", "y", "es", "del", "por", "para", "pero", "qué", "más", "su", "al",
+            "lo",
+        ],
+    ),
+    (
+        "it",
+        &[
+            "il", "gli", "di", "che", "per", "non", "sono", "della", "anche", "questo", "è", "nel",
+            "ma", "le",
+        ],
+    ),
+    (
+        "pt",
+        &[
+            "os", "um", "uma", "não", "com", "do", "da", "em", "mais", "é", "ao", "dos", "das",
+            "você",
+        ],
+    ),
+    (
+        "nl",
+        &[
+            "het", "een", "van", "niet", "dat", "op", "zijn", "voor", "ook", "wat", "hoe", "en",
+            "de", "ik",
+        ],
+    ),
+];
+
+/// A coarse guess at the ISO 639-1 language of `text`.
+///
+/// Texts are first classified by their dominant script; Latin-script texts
+/// are then told apart by counting common function words. Returns `None`
+/// when no script dominates or no language has more function words than
+/// the others, which is common for short texts.
+pub fn detect_language(text: &str) -> Option<&'static str> {
+    let mut scripts: Vec<(&'static str, usize)> = vec![];
+    for character in text.chars().filter(|c| c.is_alphabetic()) {
+        let script = script(character);
+        match scripts.iter_mut().find(|(name, _)| *name == script) {
+            Some((_, count)) => *count += 1,
+            None => scripts.push((script, 1)),
+        }
+    }
+    let letters: usize = scripts.iter().map(|(_, count)| count).sum();
+    let &(script, count) = scripts.iter().max_by_key(|(_, count)| *count)?;
+    if count * 2 <= letters {
+        return None;
+    }
+    // Japanese mixes kana with Han characters.
+    if script == "zh" && scripts.iter().any(|(name, _)| *name == "ja") {
+        return Some("ja");
+    }
+    if script != "latin" {
+        return Some(script).filter(|script| *script != "other");
+    }
+
+    let lowercase = text.to_lowercase();
+    let words: Vec<&str> = lowercase
+        .split(|c: char| !c.is_alphabetic())
+        .filter(|word| !word.is_empty())
+        .collect();
+    let mut counts: Vec<(&'static str, usize)> = STOPWORDS
+        .iter()
+        .map(|(language, stopwords)| {
+            let hits = words.iter().filter(|word| stopwords.contains(word)).count();
+            (*language, hits)
+        })
+        .collect();
+    counts.sort_by_key(|&(_, hits)| std::cmp::Reverse(hits));
+    match counts.as_slice() {
+        [(language, best), (_, second), ..] if *best > *second => Some(*language),
+        _ => None,
+    }
+}
+
+/// The language a script implies, `latin` for Latin letters, or `other`.
+fn script(character: char) -> &'static str {
+    match character as u32 {
+        0x0041..=0x024F | 0x1E00..=0x1EFF => "latin",
+        0x0370..=0x03FF | 0x1F00..=0x1FFF => "el",
+        0x0400..=0x052F => "ru",
+        0x0590..=0x05FF => "he",
+        0x0600..=0x06FF | 0x0750..=0x077F => "ar",
+        0x0900..=0x097F => "hi",
+        0x0E00..=0x0E7F => "th",
+        0x3040..=0x30FF | 0x31F0..=0x31FF => "ja",
+        0x1100..=0x11FF | 0x3130..=0x318F | 0xAC00..=0xD7AF => "ko",
+        0x3400..=0x4DBF | 0x4E00..=0x9FFF | 0xF900..=0xFAFF => "zh",
+        _ => "other",
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn standard_keeps_markup_and_quotes() {
+        let preprocessing = Preprocessing::standard();
+        assert_eq!(
+            preprocessing.apply("  \"<b>ﬁne</b>\u{7}\u{a0}text\" "),
+            "\"<b>fine</b> text\""
+        );
+    }
+
+    #[test]
+    fn strips_html_and_quotes_when_enabled() {
+        let preprocessing = Preprocessing {
+            strip_html: true,
+            strip_quotes: true,
+            ..Preprocessing::standard()
+        };
+        assert_eq!(
+            preprocessing.apply("\"<p>wor&amp;ld</p><script>var a = '<b>';</script>&#x41;\""),
+            "wor&ld A"
+        );
+        assert_eq!(preprocessing.apply("a < b and c > d"), "a < b and c > d");
+    }
+
+    #[test]
+    fn detects_language() {
+        assert_eq!(
+            detect_language("The cat is on the mat and this is fine"),
+            Some("en")
+        );
+        assert_eq!(
+            detect_language("Der Hund ist nicht mit den Katzen"),
+            Some("de")
+        );
+        assert_eq!(detect_language("これは日本語の文です"), Some("ja"));
+        assert_eq!(detect_language("42"), None);
+    }
+}
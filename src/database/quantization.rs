@@ -0,0 +1,277 @@
+/// How a collection stores document embeddings.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Quantization {
+    /// Full precision `f64` vectors.
+    #[default]
+    None,
+    /// One byte per dimension, an 8x reduction over `f64`. Queries are
+    /// scored with integer dot products against the codes.
+    ///
+    /// With `rescore: Some(n)` the full precision vectors are kept as well
+    /// and the best `n` candidates, or as many as the query pages through
+    /// if that is more, are re-scored with them; the remaining candidates
+    /// are dropped. With `None` only the codes are stored and
+    /// `Document.embedding` is returned decoded from them.
+    Int8 {
+        calibration: Calibration,
+        rescore: Option<usize>,
+    },
+    /// One bit per dimension, its sign. Queries rank the whole collection
+    /// by Hamming distance, then re-score `oversampling` times the number
+    /// of requested results with exact cosine similarity and drop the rest.
+    /// Full precision vectors are kept for the re-scoring, so this trades
+    /// memory for a fast CPU scan.
+    Binary { oversampling: usize },
+}
+
+/// How the value range each code spans is chosen.
+///
+/// The range is the min/max of the stored vectors. It is fitted once the
+/// collection holds `MIN_CALIBRATION_VECTORS` of them, which are stored in
+/// full precision until then, and again every time the collection doubles;
+/// values outside of it are clamped in between. `CosineDatabase::recalibrate`
+/// fits it again on demand.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Calibration {
+    /// A separate range for every dimension.
+    #[default]
+    PerDimension,
+    /// One range shared by all dimensions.
+    Global,
+}
+
+/// Vectors an int8 quantized collection stores before fitting its ranges,
+/// so they are not fitted on a handful of documents.
+pub const MIN_CALIBRATION_VECTORS: usize = 256;
+
+impl Quantization {
+    pub fn is_enabled(&self) -> bool {
+        *self != Quantization::None
+    }
+
+    /// Number of int8 candidates re-scored in full precision, if any.
+    pub fn rescore(&self) -> Option<usize> {
+        match *self {
+            Quantization::Int8 { rescore, .. } => rescore,
+            _ => None,
+        }
+    }
+
+    /// Whether full precision vectors are stored next to the codes.
+    pub fn keeps_full_precision(&self) -> bool {
+        match *self {
+            Quantization::Int8 { rescore, .. } => rescore.is_some(),
+            _ => true,
+        }
+    }
+
+    fn calibration(&self) -> Calibration {
+        match *self {
+            Quantization::Int8 { calibration, .. } => calibration,
+            _ => Calibration::default(),
+        }
+    }
+}
+
+/// The compressed form of one stored embedding.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Codes {
+    Int8(Vec<u8>),
+    /// Sign bits packed 64 dimensions to a word, see `binarize`.
+    Binary(Vec<u64>),
+}
+
+/// Maps each dimension linearly from `[offset, offset + 255 * scale]` onto
+/// the codes `0..=255`.
+#[derive(Debug, Clone)]
+pub struct ScalarQuantizer {
+    offsets: Vec<f64>,
+    scales: Vec<f64>,
+}
+
+impl ScalarQuantizer {
+    /// Fits the code ranges to `vectors` as configured by `quantization`.
+    /// Returns `None` when there are no vectors to fit.
+    pub fn fit<'a>(
+        vectors: impl IntoIterator<Item = &'a [f64]>,
+        quantization: &Quantization,
+    ) -> Option<ScalarQuantizer> {
+        let mut vectors = vectors.into_iter().filter(|vector| !vector.is_empty());
+        let first = vectors.next()?;
+        let mut min = first.to_vec();
+        let mut max = first.to_vec();
+        for vector in vectors {
+            for (i, &x) in vector.iter().enumerate().take(min.len()) {
+                min[i] = min[i].min(x);
+                max[i] = max[i].max(x);
+            }
+        }
+        if quantization.calibration() == Calibration::Global {
+            let global_min = min.iter().copied().fold(f64::INFINITY, f64::min);
+            let global_max = max.iter().copied().fold(f64::NEG_INFINITY, f64::max);
+            min.fill(global_min);
+            max.fill(global_max);
+        }
+
+        // A range fitted on a single vector is empty; widen it to the range
+        // of a unit vector component instead of mapping everything to one code.
+        let (offsets, scales) = min
+            .iter()
+            .zip(&max)
+            .map(|(&min, &max)| {
+                if max - min > f64::EPSILON {
+                    (min, (max - min) / 255.0)
+                } else {
+                    (-1.0, 2.0 / 255.0)
+                }
+            })
+            .unzip();
+        Some(ScalarQuantizer { offsets, scales })
+    }
+
+    pub fn dimension(&self) -> usize {
+        self.offsets.len()
+    }
+
+    pub fn encode(&self, vector: &[f64]) -> Vec<u8> {
+        vector
+            .iter()
+            .zip(self.offsets.iter().zip(&self.scales))
+            .map(|(&x, (&offset, &scale))| ((x - offset) / scale).round().clamp(0.0, 255.0) as u8)
+            .collect()
+    }
+
+    pub fn decode(&self, codes: &[u8]) -> Vec<f64> {
+        codes
+            .iter()
+            .zip(self.offsets.iter().zip(&self.scales))
+            .map(|(&code, (&offset, &scale))| offset + scale * code as f64)
+            .collect()
+    }
+
+    /// Prepares `query` for scoring against codes. The dot product with a
+    /// decoded vector is `sum(q * scale * code) + sum(q * offset)`; the
+    /// per-dimension weights `q * scale` are themselves quantized to `i8` so
+    /// the first sum is an integer dot product.
+    pub fn query(&self, query: &[f64]) -> QuantizedQuery {
+        let weights: Vec<f64> = query.iter().zip(&self.scales).map(|(q, s)| q * s).collect();
+        let bias = query.iter().zip(&self.offsets).map(|(q, o)| q * o).sum();
+        let max_weight = weights.iter().fold(0.0_f64, |max, w| max.max(w.abs()));
+        let scale = if max_weight > 0.0 {
+            max_weight / 127.0
+        } else {
+            1.0
+        };
+        QuantizedQuery {
+            weights: weights
+                .iter()
+                .map(|w| (w / scale).round().clamp(-127.0, 127.0) as i8)
+                .collect(),
+            scale,
+            bias,
+        }
+    }
+}
+
+/// A query vector prepared by `ScalarQuantizer::query`.
+pub struct QuantizedQuery {
+    weights: Vec<i8>,
+    scale: f64,
+    bias: f64,
+}
+
+impl QuantizedQuery {
+    /// Approximate dot product of the query with the vector behind `codes`.
+    pub fn score(&self, codes: &[u8]) -> f64 {
+        self.scale * dot_u8_i8(codes, &self.weights) as f64 + self.bias
+    }
+}
+
+/// Integer dot product of unsigned codes with signed weights, using AVX2
+/// when the CPU has it.
+pub fn dot_u8_i8(codes: &[u8], weights: &[i8]) -> i32 {
+    let length = codes.len().min(weights.len());
+    let (codes, weights) = (&codes[..length], &weights[..length]);
+    #[cfg(target_arch = "x86_64")]
+    if is_x86_feature_detected!("avx2") {
+        // SAFETY: AVX2 support was checked just above.
+        return unsafe { dot_u8_i8_avx2(codes, weights) };
+    }
+    dot_u8_i8_scalar(codes, weights)
+}
+
+fn dot_u8_i8_scalar(codes: &[u8], weights: &[i8]) -> i32 {
+    codes
+        .iter()
+        .zip(weights)
+        .map(|(&c, &w)| c as i32 * w as i32)
+        .sum()
+}
+
+/// Widens 16 codes and weights at a time to `i16` and accumulates pairwise
+/// products into `i32` lanes with `madd`, which cannot saturate: a pair sums
+/// to at most `2 * 255 * 127`.
+#[cfg(target_arch = "x86_64")]
+#[target_feature(enable = "avx2")]
+fn dot_u8_i8_avx2(codes: &[u8], weights: &[i8]) -> i32 {
+    use std::arch::x86_64::*;
+
+    let blocks = codes.len() / 16;
+    let mut sums = _mm256_setzero_si256();
+    for block in 0..blocks {
+        let offset = block * 16;
+        // SAFETY: `offset + 16 <= codes.len() == weights.len()`, and the
+        // loads are unaligned.
+        let (c, w) = unsafe {
+            (
+                _mm_loadu_si128(codes.as_ptr().add(offset) as *const __m128i),
+                _mm_loadu_si128(weights.as_ptr().add(offset) as *const __m128i),
+            )
+        };
+        let products = _mm256_madd_epi16(_mm256_cvtepu8_epi16(c), _mm256_cvtepi8_epi16(w));
+        sums = _mm256_add_epi32(sums, products);
+    }
+
+    let mut lanes = [0i32; 8];
+    // SAFETY: `lanes` holds exactly one unaligned 256-bit value.
+    unsafe { _mm256_storeu_si256(lanes.as_mut_ptr() as *mut __m256i, sums) };
+    let tail = blocks * 16;
+    lanes.iter().sum::<i32>() + dot_u8_i8_scalar(&codes[tail..], &weights[tail..])
+}
+
+/// Packs the sign of each component into bits: bit `i % 64` of word
+/// `i / 64` is set when component `i` is positive.
+pub fn binarize(vector: &[f64]) -> Vec<u64> {
+    vector
+        .chunks(64)
+        .map(|chunk| {
+            chunk
+                .iter()
+                .enumerate()
+                .filter(|(_, x)| **x > 0.0)
+                .fold(0, |word, (i, _)| word | (1 << i))
+        })
+        .collect()
+}
+
+/// Number of differing bits, using the POPCNT instruction when the CPU
+/// has it.
+pub fn hamming_distance(a: &[u64], b: &[u64]) -> u32 {
+    #[cfg(target_arch = "x86_64")]
+    if is_x86_feature_detected!("popcnt") {
+        // SAFETY: POPCNT support was checked just above.
+        return unsafe { hamming_distance_popcnt(a, b) };
+    }
+    count_differing_bits(a, b)
+}
+
+#[inline(always)]
+fn count_differing_bits(a: &[u64], b: &[u64]) -> u32 {
+    a.iter().zip(b).map(|(a, b)| (a ^ b).count_ones()).sum()
+}
+
+#[cfg(target_arch = "x86_64")]
+#[target_feature(enable = "popcnt")]
+fn hamming_distance_popcnt(a: &[u64], b: &[u64]) -> u32 {
+    count_differing_bits(a, b)
+}
@@ -0,0 +1,188 @@
+use tch::{Kind, Tensor};
+
+/// Shrinks document embeddings before they are stored. Queries go through
+/// the same transform automatically. Embeddings supplied by callers are
+/// given in the model's dimension, or already truncated for `Truncate`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum DimensionReduction {
+    #[default]
+    None,
+    /// Keeps the first `dimension` components and renormalises, for models
+    /// trained with Matryoshka representation learning.
+    Truncate { dimension: usize },
+    /// Projects onto the top `dimension` principal components of the stored
+    /// vectors. The projection is fitted once the collection holds at least
+    /// `dimension` documents, which are kept in the model's dimension until
+    /// then, and is kept until the collection is cleared or
+    /// `CosineDatabase::refit` fits it again.
+    Pca { dimension: usize },
+}
+
+impl DimensionReduction {
+    pub fn is_enabled(&self) -> bool {
+        *self != DimensionReduction::None
+    }
+
+    /// Dimension of the stored vectors, if reduced.
+    pub fn dimension(&self) -> Option<usize> {
+        match *self {
+            DimensionReduction::None => None,
+            DimensionReduction::Truncate { dimension } | DimensionReduction::Pca { dimension } => {
+                Some(dimension)
+            }
+        }
+    }
+}
+
+/// A fitted `DimensionReduction`.
+#[derive(Debug, Clone)]
+pub enum Reducer {
+    Truncate { dimension: usize },
+    Pca(Pca),
+}
+
+impl Reducer {
+    /// The reducer for `reduction` if it needs no training data.
+    pub fn untrained(reduction: &DimensionReduction) -> Option<Reducer> {
+        match *reduction {
+            DimensionReduction::Truncate { dimension } => Some(Reducer::Truncate { dimension }),
+            _ => None,
+        }
+    }
+
+    /// Transforms `vector`. `Truncate` takes vectors of exactly `dimension`
+    /// as already truncated, as callers may supply them; PCA only takes
+    /// vectors of the dimension it was fitted on.
+    pub fn apply(&self, vector: &[f64]) -> Result<Vec<f64>, String> {
+        match self {
+            Reducer::Truncate { dimension } => {
+                if vector.len() < *dimension {
+                    return Err(format!(
+                        "Cannot truncate a {}-dimensional embedding to {} dimensions",
+                        vector.len(),
+                        dimension
+                    ));
+                }
+                let mut truncated = vector[..*dimension].to_vec();
+                let norm = truncated.iter().map(|x| x * x).sum::<f64>().sqrt();
+                if norm > 0.0 {
+                    truncated.iter_mut().for_each(|x| *x /= norm);
+                }
+                Ok(truncated)
+            }
+            Reducer::Pca(pca) => {
+                if vector.len() != pca.input_dimension {
+                    return Err(format!(
+                        "Cannot project a {}-dimensional embedding with a PCA fitted on {} dimensions",
+                        vector.len(),
+                        pca.input_dimension
+                    ));
+                }
+                Ok(pca.project(vector))
+            }
+        }
+    }
+}
+
+/// Projection onto principal components: `components * (vector - mean)`.
+#[derive(Debug, Clone)]
+pub struct Pca {
+    input_dimension: usize,
+    mean: Vec<f64>,
+    /// `[output dimension, input dimension]`, row major.
+    components: Vec<f64>,
+}
+
+impl Pca {
+    /// Fits the top `dimension` components of `vectors` from the
+    /// eigenvectors of their covariance matrix. Fails when there are no
+    /// vectors or they differ in length.
+    pub fn fit(vectors: &[&[f64]], dimension: usize) -> Result<Pca, String> {
+        let input_dimension = vectors
+            .first()
+            .ok_or("Cannot fit PCA without vectors")?
+            .len();
+        if let Some(vector) = vectors
+            .iter()
+            .find(|vector| vector.len() != input_dimension)
+        {
+            return Err(format!(
+                "Cannot fit PCA on vectors of {} and {} dimensions",
+                input_dimension,
+                vector.len()
+            ));
+        }
+        let dimension = dimension.min(input_dimension);
+
+        let flat: Vec<f64> = vectors
+            .iter()
+            .flat_map(|vector| vector.iter())
+            .copied()
+            .collect();
+        let matrix = Tensor::from_slice(&flat)
+            .to_kind(Kind::Double)
+            .view([vectors.len() as i64, input_dimension as i64]);
+        let mean = matrix.mean_dim(0, true, Kind::Double);
+        let centered = &matrix - &mean;
+        let covariance = centered.tr().matmul(&centered) / vectors.len() as f64;
+        // Eigenvalues come in ascending order, one eigenvector per column.
+        let (_, eigenvectors) = covariance.linalg_eigh("L");
+        let components = eigenvectors
+            .flip([1])
+            .narrow(1, 0, dimension as i64)
+            .tr()
+            .contiguous();
+
+        Ok(Pca {
+            input_dimension,
+            mean: Vec::<f64>::try_from(mean.view([-1])).map_err(|e| e.to_string())?,
+            components: Vec::<f64>::try_from(components.view([-1])).map_err(|e| e.to_string())?,
+        })
+    }
+
+    pub fn output_dimension(&self) -> usize {
+        self.components.len() / self.input_dimension.max(1)
+    }
+
+    pub fn project(&self, vector: &[f64]) -> Vec<f64> {
+        let centered: Vec<f64> = vector.iter().zip(&self.mean).map(|(x, m)| x - m).collect();
+        self.components
+            .chunks_exact(self.input_dimension.max(1))
+            .map(|component| component.iter().zip(&centered).map(|(c, x)| c * x).sum())
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn pca_rejects_mixed_lengths() {
+        let vectors: Vec<Vec<f64>> = vec![
+            vec![1.0, 0.0, 0.0],
+            vec![0.0, 2.0, 0.0],
+            vec![0.0, 0.0, 3.0],
+        ];
+        let slices: Vec<&[f64]> = vectors.iter().map(Vec::as_slice).collect();
+        let pca = Pca::fit(&slices, 2).unwrap();
+        assert_eq!(pca.output_dimension(), 2);
+
+        let reducer = Reducer::Pca(pca);
+        assert_eq!(reducer.apply(&[1.0, 1.0, 1.0]).unwrap().len(), 2);
+        assert!(reducer.apply(&[1.0, 1.0]).is_err());
+        assert!(reducer.apply(&[1.0, 1.0, 1.0, 1.0]).is_err());
+
+        let mixed: Vec<&[f64]> = vec![&[1.0, 0.0, 0.0], &[0.0, 2.0], &[0.0, 0.0, 3.0]];
+        assert!(Pca::fit(&mixed, 2).is_err());
+        assert!(Pca::fit(&[], 2).is_err());
+    }
+
+    #[test]
+    fn truncation_keeps_truncated_vectors() {
+        let reducer = Reducer::Truncate { dimension: 2 };
+        assert_eq!(reducer.apply(&[3.0, 4.0, 5.0]).unwrap(), [0.6, 0.8]);
+        assert_eq!(reducer.apply(&[0.6, 0.8]).unwrap(), [0.6, 0.8]);
+        assert!(reducer.apply(&[1.0]).is_err());
+    }
+}
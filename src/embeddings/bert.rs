@@ -1,15 +1,20 @@
 use std::path::Path;
 
-use rust_bert::Config;
 use rust_bert::bert::{BertConfig, BertEmbeddings, BertModel};
-use tch::nn::VarStore;
-use tch::{Device, Tensor, nn, no_grad};
-
-use rust_tokenizers::preprocessing::tokenizer::base_tokenizer::{
-    MultiThreadedTokenizer, Tokenizer,
+use rust_bert::distilbert::{DistilBertConfig, DistilBertModel};
+use rust_bert::roberta::{RobertaConfig, RobertaEmbeddings};
+use rust_tokenizers::Offset;
+use rust_tokenizers::tokenizer::{
+    BertTokenizer, MultiThreadedTokenizer, RobertaTokenizer, Tokenizer,
 };
-use rust_tokenizers::{BertTokenizer, BertVocab};
+use rust_tokenizers::vocab::Vocab;
+use serde::Deserialize;
 use tch::index::IndexOp;
+use tch::nn::VarStore;
+use tch::{Device, TchError, Tensor, nn, no_grad};
+
+use super::checkpoint::{load_weights, read_json};
+use super::mpnet::{MPNetConfig, MPNetModel};
 
 #[derive(Debug, Default)]
 pub struct Features {
@@ -56,12 +61,238 @@ impl Features {
     }
 }
 
+/// Transformer architectures that can back a sentence-transformer checkpoint.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ModelType {
+    Bert,
+    DistilBert,
+    Roberta,
+    MPNet,
+}
+
+impl ModelType {
+    /// Reads the architecture from `config.json`, using `model_type` when
+    /// present and the `architectures` list otherwise. Checkpoints that name
+    /// neither are treated as BERT, which is what older exports look like.
+    pub fn detect(config_path: &Path) -> Result<ModelType, TchError> {
+        let config: ModelTypeConfig = read_json(config_path)?;
+        let name = match (config.model_type, config.architectures.first()) {
+            (Some(model_type), _) => model_type.to_lowercase(),
+            (None, Some(architecture)) => architecture.to_lowercase(),
+            (None, None) => return Ok(ModelType::Bert),
+        };
+
+        if name.starts_with("distilbert") {
+            Ok(ModelType::DistilBert)
+        } else if name.starts_with("roberta") {
+            Ok(ModelType::Roberta)
+        } else if name.starts_with("mpnet") {
+            Ok(ModelType::MPNet)
+        } else if name.starts_with("bert") {
+            Ok(ModelType::Bert)
+        } else {
+            Err(TchError::FileFormat(format!(
+                "unsupported model type `{}` in {} (expected bert, distilbert, roberta or mpnet)",
+                name,
+                config_path.display()
+            )))
+        }
+    }
+
+    /// Path rust-bert registers the encoder's variables under.
+    pub fn prefix(&self) -> &'static str {
+        match self {
+            ModelType::Bert => "bert",
+            ModelType::DistilBert => "distilbert",
+            ModelType::Roberta => "roberta",
+            ModelType::MPNet => "mpnet",
+        }
+    }
+
+    /// Whether the tokenizer lower-cases unless the checkpoint says otherwise.
+    fn default_lower_case(&self) -> bool {
+        !matches!(self, ModelType::Roberta)
+    }
+}
+
+#[derive(Deserialize)]
+struct ModelTypeConfig {
+    model_type: Option<String>,
+    #[serde(default)]
+    architectures: Vec<String>,
+}
+
+#[derive(Deserialize)]
+struct TokenizerConfig {
+    do_lower_case: Option<bool>,
+}
+
+/// Settings sentence-transformers saves next to the transformer's
+/// `config.json`, overriding the tokenizer's own.
+#[derive(Deserialize, Default)]
+struct SentenceBertConfig {
+    max_seq_length: Option<i64>,
+    do_lower_case: Option<bool>,
+}
+
+impl SentenceBertConfig {
+    fn load(model_path: &Path) -> Result<SentenceBertConfig, TchError> {
+        let path = model_path.join("sentence_bert_config.json");
+        if !path.exists() {
+            return Ok(SentenceBertConfig::default());
+        }
+        read_json(&path)
+    }
+}
+
+/// Whether to lower-case input: an explicit setting wins, then the
+/// checkpoint's `tokenizer_config.json`, then the architecture's default.
+pub(crate) fn resolve_lower_case(
+    model_path: &Path,
+    do_lower_case: Option<bool>,
+    default: bool,
+) -> Result<bool, TchError> {
+    let tokenizer_config_path = model_path.join("tokenizer_config.json");
+    match do_lower_case {
+        Some(value) => Ok(value),
+        None if tokenizer_config_path.exists() => {
+            let config: TokenizerConfig = read_json(&tokenizer_config_path)?;
+            Ok(config.do_lower_case.unwrap_or(default))
+        }
+        None => Ok(default),
+    }
+}
+
+/// Drops tokens from the end of the longer segment until the pair holds at
+/// most `max_tokens` tokens.
+pub(crate) fn truncate_pair<'a>(
+    first: &'a [i64],
+    second: &'a [i64],
+    max_tokens: usize,
+) -> (&'a [i64], &'a [i64]) {
+    let (mut first_len, mut second_len) = (first.len(), second.len());
+    while first_len + second_len > max_tokens {
+        if first_len >= second_len {
+            first_len -= 1;
+        } else {
+            second_len -= 1;
+        }
+    }
+    (&first[..first_len], &second[..second_len])
+}
+
+/// Builds `[CLS] first [SEP] second [SEP]` inputs padded to `pad_length`,
+/// with `token_type_ids` of 1 (segment B) on the second segment.
+pub(crate) fn pair_features(
+    first: &[i64],
+    second: &[i64],
+    pad_length: usize,
+    (cls_token_id, sep_token_id, pad_token_id): (i64, i64, i64),
+) -> (Vec<i64>, Vec<i64>, Vec<i64>) {
+    let mut input_ids = vec![cls_token_id];
+    input_ids.extend_from_slice(first);
+    input_ids.push(sep_token_id);
+    let mut token_type_ids = vec![0; input_ids.len()];
+
+    input_ids.extend_from_slice(second);
+    input_ids.push(sep_token_id);
+    token_type_ids.resize(input_ids.len(), 1);
+    let mut input_mask = vec![1; input_ids.len()];
+
+    let padding = pad_length.saturating_sub(input_ids.len());
+    input_ids.extend(vec![pad_token_id; padding]);
+    token_type_ids.extend(vec![0; padding]);
+    input_mask.extend(vec![0; padding]);
+
+    (input_ids, token_type_ids, input_mask)
+}
+
+enum Encoder {
+    Bert(BertModel<BertEmbeddings>),
+    DistilBert(DistilBertModel),
+    Roberta(BertModel<RobertaEmbeddings>),
+    MPNet(MPNetModel),
+}
+
+enum TextTokenizer {
+    Bert(Box<BertTokenizer>),
+    Roberta(Box<RobertaTokenizer>),
+}
+
+impl TextTokenizer {
+    fn tokenize(&self, text: &str) -> Vec<i64> {
+        match self {
+            TextTokenizer::Bert(tokenizer) => {
+                tokenizer.convert_tokens_to_ids(&tokenizer.tokenize(text))
+            }
+            TextTokenizer::Roberta(tokenizer) => {
+                tokenizer.convert_tokens_to_ids(&tokenizer.tokenize(text))
+            }
+        }
+    }
+
+    /// Token ids with the character span of each token, `None` for tokens
+    /// that do not map back to the text.
+    fn tokenize_with_offsets(&self, text: &str) -> (Vec<i64>, Vec<Option<Offset>>) {
+        match self {
+            TextTokenizer::Bert(tokenizer) => {
+                let tokens = tokenizer.tokenize_with_offsets(text);
+                (
+                    tokenizer.convert_tokens_to_ids(&tokens.tokens),
+                    tokens.offsets,
+                )
+            }
+            TextTokenizer::Roberta(tokenizer) => {
+                let tokens = tokenizer.tokenize_with_offsets(text);
+                (
+                    tokenizer.convert_tokens_to_ids(&tokens.tokens),
+                    tokens.offsets,
+                )
+            }
+        }
+    }
+
+    fn tokenize_list(&self, text_list: &[&str]) -> Vec<Vec<i64>> {
+        match self {
+            TextTokenizer::Bert(tokenizer) => {
+                MultiThreadedTokenizer::tokenize_list(tokenizer.as_ref(), text_list)
+                    .iter()
+                    .map(|sentence_tokens| tokenizer.convert_tokens_to_ids(sentence_tokens))
+                    .collect()
+            }
+            TextTokenizer::Roberta(tokenizer) => {
+                MultiThreadedTokenizer::tokenize_list(tokenizer.as_ref(), text_list)
+                    .iter()
+                    .map(|sentence_tokens| tokenizer.convert_tokens_to_ids(sentence_tokens))
+                    .collect()
+            }
+        }
+    }
+
+    fn token_id(&self, token: &str) -> i64 {
+        match self {
+            TextTokenizer::Bert(tokenizer) => {
+                Tokenizer::vocab(tokenizer.as_ref()).token_to_id(token)
+            }
+            TextTokenizer::Roberta(tokenizer) => {
+                Tokenizer::vocab(tokenizer.as_ref()).token_to_id(token)
+            }
+        }
+    }
+}
+
+/// The transformer half of a sentence-transformer: one of the supported
+/// encoders plus its tokenizer. The name predates DistilBERT, RoBERTa and
+/// MPNet support.
 pub struct Bert {
-    pub bert: BertModel<BertEmbeddings>,
-    tokenizer: BertTokenizer,
+    pub model_type: ModelType,
+    encoder: Encoder,
+    tokenizer: TextTokenizer,
     max_seq_length: i64,
+    do_lower_case: bool,
     cls_token_id: i64,
     sep_token_id: i64,
+    pad_token_id: i64,
     pub vs: VarStore,
 }
 
@@ -71,17 +302,12 @@ impl Bert {
         max_seq_length: Option<i64>,
         do_lower_case: Option<bool>,
         device: Device,
-    ) -> Bert {
-        let max_seq_length = if let Some(value) = max_seq_length {
-            value
-        } else {
-            128
-        };
-        let do_lower_case = if let Some(value) = do_lower_case {
-            value
-        } else {
-            true
-        };
+    ) -> Result<Bert, TchError> {
+        // Explicit settings win over the checkpoint's sentence_bert_config.json.
+        let sentence_config = SentenceBertConfig::load(model_path)?;
+        let max_seq_length = max_seq_length
+            .or(sentence_config.max_seq_length)
+            .unwrap_or(128);
 
         let max_seq_length = if max_seq_length > 510 {
             println!(
@@ -94,47 +320,134 @@ impl Bert {
 
         let mut vs = nn::VarStore::new(device);
 
-        let bert_config_path = model_path.join("config.json");
-        let bert_vocab_path = model_path.join("vocab.txt");
-        let weights_path = model_path.join("rust_model.ot");
+        let config_path = model_path.join("config.json");
+        let model_type = ModelType::detect(&config_path)?;
 
-        let bert_config = BertConfig::from_file(bert_config_path.as_path());
-        let bert: BertModel<BertEmbeddings> = BertModel::new(&(&vs.root() / "bert"), &bert_config);
+        let do_lower_case = resolve_lower_case(
+            model_path,
+            do_lower_case.or(sentence_config.do_lower_case),
+            model_type.default_lower_case(),
+        )?;
 
-        let tokenizer = BertTokenizer::from_file(bert_vocab_path.to_str().unwrap(), do_lower_case);
-        let cls_token_id =
-            tokenizer.convert_tokens_to_ids(&[String::from(BertVocab::cls_value())].to_vec())[0];
-        let sep_token_id =
-            tokenizer.convert_tokens_to_ids(&[String::from(BertVocab::sep_value())].to_vec())[0];
+        let encoder = match model_type {
+            ModelType::Bert => Encoder::Bert(BertModel::new_with_optional_pooler(
+                &vs.root() / "bert",
+                &read_json::<BertConfig>(&config_path)?,
+                false,
+            )),
+            // DistilBertModel adds its own "distilbert" prefix
+            ModelType::DistilBert => Encoder::DistilBert(DistilBertModel::new(
+                vs.root(),
+                &read_json::<DistilBertConfig>(&config_path)?,
+            )),
+            ModelType::Roberta => Encoder::Roberta(BertModel::new_with_optional_pooler(
+                &vs.root() / "roberta",
+                &read_json::<RobertaConfig>(&config_path)?,
+                false,
+            )),
+            ModelType::MPNet => Encoder::MPNet(MPNetModel::new(
+                &vs.root() / "mpnet",
+                &read_json::<MPNetConfig>(&config_path)?,
+            )),
+        };
 
-        vs.load(Path::new(&weights_path))
-            .expect("Failed to load weights!");
+        let tokenizer = match model_type {
+            ModelType::Roberta => TextTokenizer::Roberta(Box::new(
+                RobertaTokenizer::from_file(
+                    model_path.join("vocab.json"),
+                    model_path.join("merges.txt"),
+                    do_lower_case,
+                    false,
+                )
+                .map_err(|e| TchError::FileFormat(e.to_string()))?,
+            )),
+            _ => TextTokenizer::Bert(Box::new(
+                BertTokenizer::from_file(
+                    model_path.join("vocab.txt"),
+                    do_lower_case,
+                    do_lower_case,
+                )
+                .map_err(|e| TchError::FileFormat(e.to_string()))?,
+            )),
+        };
+
+        // MPNet keeps a WordPiece vocabulary but RoBERTa's special tokens
+        let (cls, sep, pad) = match model_type {
+            ModelType::Bert | ModelType::DistilBert => ("[CLS]", "[SEP]", "[PAD]"),
+            ModelType::Roberta | ModelType::MPNet => ("<s>", "</s>", "<pad>"),
+        };
+        let cls_token_id = tokenizer.token_id(cls);
+        let sep_token_id = tokenizer.token_id(sep);
+        let pad_token_id = tokenizer.token_id(pad);
 
-        Bert {
-            bert,
+        load_weights(&mut vs, model_path, Some(model_type.prefix()))?;
+
+        Ok(Bert {
+            model_type,
+            encoder,
             tokenizer,
             max_seq_length,
+            do_lower_case,
             cls_token_id,
             sep_token_id,
+            pad_token_id,
             vs,
-        }
+        })
     }
 
     pub fn forward_t(&self, features: Features) -> Features {
-        let (output_tokens, _, _, _) = no_grad(|| {
-            self.bert
-                .forward_t(
-                    Some(features.input_ids.as_ref().unwrap().shallow_clone()),
-                    Some(features.input_mask.as_ref().unwrap().shallow_clone()),
-                    Some(features.token_type_ids.as_ref().unwrap().shallow_clone()),
-                    None,
-                    None,
-                    &None,
-                    &None, //Some(features.input_mask.as_ref().unwrap().shallow_clone()),
-                    false,
-                )
-                .unwrap()
-        });
+        no_grad(|| self.forward(features, false))
+    }
+
+    /// `forward_t` with dropout and gradient tracking, for fine-tuning.
+    pub fn forward_train(&self, features: Features) -> Features {
+        self.forward(features, true)
+    }
+
+    fn forward(&self, features: Features, train: bool) -> Features {
+        let input_ids = features.input_ids.as_ref().unwrap();
+        let input_mask = features.input_mask.as_ref().unwrap();
+        let token_type_ids = features.token_type_ids.as_ref();
+
+        let output_tokens = match &self.encoder {
+            Encoder::Bert(model) => {
+                model
+                    .forward_t(
+                        Some(input_ids),
+                        Some(input_mask),
+                        token_type_ids,
+                        None,
+                        None,
+                        None,
+                        None,
+                        train,
+                    )
+                    .unwrap()
+                    .hidden_state
+            }
+            Encoder::Roberta(model) => {
+                model
+                    .forward_t(
+                        Some(input_ids),
+                        Some(input_mask),
+                        None,
+                        None,
+                        None,
+                        None,
+                        None,
+                        train,
+                    )
+                    .unwrap()
+                    .hidden_state
+            }
+            Encoder::DistilBert(model) => {
+                model
+                    .forward_t(Some(input_ids), Some(input_mask), None, train)
+                    .unwrap()
+                    .hidden_state
+            }
+            Encoder::MPNet(model) => model.forward_t(input_ids, input_mask, train),
+        };
 
         let cls_token = output_tokens.i((.., 0, ..)); //CLS token is first token
 
@@ -145,16 +458,46 @@ impl Bert {
         }
     }
 
+    /// Most tokens of a text that fit in one forward pass, excluding the
+    /// added special tokens.
+    pub fn max_seq_length(&self) -> usize {
+        self.max_seq_length as usize
+    }
+
+    /// Whether the tokenizer lower-cases its input.
+    pub fn do_lower_case(&self) -> bool {
+        self.do_lower_case
+    }
+
     pub fn tokenize(&self, text: &str) -> Vec<i64> {
-        self.tokenizer
-            .convert_tokens_to_ids(&self.tokenizer.tokenize(text))
+        self.tokenizer.tokenize(text)
+    }
+
+    /// `tokenize` plus the byte range of `text` each token came from, `None`
+    /// for tokens with no counterpart in the text.
+    pub fn tokenize_with_spans(&self, text: &str) -> (Vec<i64>, Vec<Option<(usize, usize)>>) {
+        let (ids, offsets) = self.tokenizer.tokenize_with_offsets(text);
+        // Offsets count characters; map them to byte positions.
+        let positions: Vec<usize> = text
+            .char_indices()
+            .map(|(position, _)| position)
+            .chain([text.len()])
+            .collect();
+        let spans = offsets
+            .into_iter()
+            .map(|offset| {
+                let offset = offset?;
+                Some((
+                    *positions.get(offset.begin as usize)?,
+                    *positions.get(offset.end as usize)?,
+                ))
+            })
+            .collect();
+        (ids, spans)
     }
 
     pub fn tokenize_multithreaded(&self, text_list: Vec<&str>) -> Vec<Vec<i64>> {
-        MultiThreadedTokenizer::tokenize_list(&self.tokenizer, text_list)
-            .iter()
-            .map(|sentence_tokens| self.tokenizer.convert_tokens_to_ids(sentence_tokens))
-            .collect()
+        self.tokenizer.tokenize_list(&text_list)
     }
 
     pub fn get_sentence_features(
@@ -182,11 +525,12 @@ impl Bert {
         let mut token_type_ids = vec![0; input_ids.len()];
         let mut input_mask = vec![1; input_ids.len()];
 
-        // Zero-pad up to the sequence length. Bert: Pad to the right
-        let padding = vec![0; pad_seq_length as usize - input_ids.len()];
-        input_ids.extend(&padding);
-        token_type_ids.extend(&padding);
-        input_mask.extend(&padding);
+        // Pad to the right. RoBERTa and MPNet derive position ids from the
+        // pad token, so input ids use it rather than 0.
+        let padding = pad_seq_length as usize - input_ids.len();
+        input_ids.extend(vec![self.pad_token_id; padding]);
+        token_type_ids.extend(vec![0; padding]);
+        input_mask.extend(vec![0; padding]);
 
         assert_eq!(input_ids.len(), pad_seq_length);
         assert_eq!(input_mask.len(), pad_seq_length);
@@ -0,0 +1,501 @@
+use crate::embeddings::{Embedder, SlidingWindow, TokenEmbedding};
+use sha2::{Digest, Sha256};
+use std::collections::{BTreeMap, HashMap};
+use std::convert::Infallible;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::SystemTime;
+
+/// Name of the file recording which model a disk cache directory belongs to.
+const MODEL_ID_FILE: &str = "MODEL_ID";
+const VECTOR_EXTENSION: &str = "f64";
+
+/// Distinguishes temporary files of concurrent writes of the same vector.
+static NEXT_TEMP_FILE: AtomicU64 = AtomicU64::new(0);
+
+/// Hit and miss counts of a `CachedEmbedder`.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct CacheStats {
+    /// Texts answered from memory.
+    pub hits: u64,
+    /// Texts answered from the disk tier.
+    pub disk_hits: u64,
+    /// Texts that had to be embedded by the model.
+    pub misses: u64,
+    /// Entries dropped from memory to stay within capacity.
+    pub evictions: u64,
+}
+
+/// Embedding cache in front of another `Embedder`.
+///
+/// Entries are keyed by the model id and a hash of the whitespace
+/// normalised text, so the same text is only embedded once per model. An
+/// in-memory LRU tier holds up to `capacity` vectors; an optional disk tier
+/// keeps them across restarts and is wiped when it was written by a
+/// different model. Vectors are written to disk atomically, so a crash
+/// never leaves a partial file behind, and outside the cache lock.
+pub struct CachedEmbedder<E: Embedder> {
+    inner: E,
+    model_id: String,
+    dimension: usize,
+    state: Mutex<CacheState>,
+    disk: Option<PathBuf>,
+    /// Size in bytes the disk tier is pruned back under, if limited.
+    disk_limit: Option<u64>,
+    /// Bytes written to the disk tier, recounted whenever it is pruned.
+    disk_usage: AtomicU64,
+}
+
+#[derive(Default)]
+struct CacheState {
+    memory: Lru,
+    stats: CacheStats,
+}
+
+impl<E: Embedder> CachedEmbedder<E> {
+    pub fn new(inner: E, capacity: usize) -> CachedEmbedder<E> {
+        let model_id = inner.model_id();
+        let dimension = inner.dimension();
+        CachedEmbedder {
+            inner,
+            model_id,
+            dimension,
+            state: Mutex::new(CacheState {
+                memory: Lru::new(capacity),
+                stats: CacheStats::default(),
+            }),
+            disk: None,
+            disk_limit: None,
+            disk_usage: AtomicU64::new(0),
+        }
+    }
+
+    /// Adds a disk tier in `directory`, clearing it first if it holds
+    /// vectors from another model.
+    pub fn with_disk(mut self, directory: &Path) -> io::Result<CachedEmbedder<E>> {
+        fs::create_dir_all(directory)?;
+        let marker = directory.join(MODEL_ID_FILE);
+        let cached_model = fs::read_to_string(&marker).ok();
+        if cached_model.as_deref() != Some(self.model_id.as_str()) {
+            clear_directory(directory)?;
+            fs::write(&marker, &self.model_id)?;
+        }
+        let usage = vector_files(directory)?.iter().map(|file| file.size).sum();
+        self.disk_usage = AtomicU64::new(usage);
+        self.disk = Some(directory.to_path_buf());
+        Ok(self)
+    }
+
+    /// Caps the disk tier at about `bytes`: once it grows past them, the
+    /// least recently written vectors are deleted until it is back under
+    /// nine tenths of the limit.
+    pub fn with_disk_limit(mut self, bytes: u64) -> CachedEmbedder<E> {
+        self.disk_limit = Some(bytes);
+        self
+    }
+
+    pub fn stats(&self) -> CacheStats {
+        self.state.lock().unwrap().stats
+    }
+
+    /// Drops every cached vector, in memory and on disk.
+    pub fn invalidate(&self) -> io::Result<()> {
+        self.state.lock().unwrap().memory.clear();
+        if let Some(directory) = &self.disk {
+            clear_directory(directory)?;
+            fs::write(directory.join(MODEL_ID_FILE), &self.model_id)?;
+            self.disk_usage.store(0, Ordering::Relaxed);
+        }
+        Ok(())
+    }
+
+    pub fn inner(&self) -> &E {
+        &self.inner
+    }
+
+    /// Cache key of `text` embedded in `mode`: empty for `embed_batch`, or
+    /// describing the window for `embed_windowed`.
+    fn key(&self, text: &str, mode: &str) -> String {
+        let normalized = text.split_whitespace().collect::<Vec<_>>().join(" ");
+        let mut hasher = Sha256::new();
+        hasher.update(self.model_id.as_bytes());
+        hasher.update([0]);
+        if !mode.is_empty() {
+            hasher.update(mode.as_bytes());
+            hasher.update([0]);
+        }
+        hasher.update(normalized.as_bytes());
+        format!("{:x}", hasher.finalize())
+    }
+
+    fn disk_path(&self, key: &str) -> Option<PathBuf> {
+        self.disk.as_ref().map(|directory| {
+            directory
+                .join(&key[..2])
+                .join(format!("{}.{}", key, VECTOR_EXTENSION))
+        })
+    }
+
+    /// The vector stored for `key`; files of the wrong size, e.g. from a
+    /// model with another dimension, are ignored.
+    fn read_disk(&self, key: &str) -> Option<Vec<f64>> {
+        let bytes = fs::read(self.disk_path(key)?).ok()?;
+        if bytes.len() != self.dimension * 8 {
+            return None;
+        }
+        Some(
+            bytes
+                .chunks_exact(8)
+                .map(|chunk| f64::from_le_bytes(chunk.try_into().unwrap()))
+                .collect(),
+        )
+    }
+
+    /// Best effort: a vector that fails to persist is still cached in memory.
+    /// The vector is written to a temporary file first and renamed into
+    /// place, so readers never see a partial file.
+    fn write_disk(&self, key: &str, embedding: &[f64]) {
+        let Some(path) = self.disk_path(key) else {
+            return;
+        };
+        let bytes: Vec<u8> = embedding.iter().flat_map(|x| x.to_le_bytes()).collect();
+        let temp_path = path.with_extension(format!(
+            "{}.tmp",
+            NEXT_TEMP_FILE.fetch_add(1, Ordering::Relaxed)
+        ));
+        let written = path
+            .parent()
+            .map_or(Ok(()), fs::create_dir_all)
+            .and_then(|_| fs::write(&temp_path, &bytes))
+            .and_then(|_| fs::rename(&temp_path, &path));
+        if written.is_err() {
+            let _ = fs::remove_file(&temp_path);
+            return;
+        }
+        let usage = self
+            .disk_usage
+            .fetch_add(bytes.len() as u64, Ordering::Relaxed)
+            + bytes.len() as u64;
+        if self.disk_limit.is_some_and(|limit| usage > limit) {
+            self.prune_disk();
+        }
+    }
+
+    /// Deletes the least recently written vectors until the disk tier is
+    /// under nine tenths of its limit.
+    fn prune_disk(&self) {
+        let (Some(directory), Some(limit)) = (&self.disk, self.disk_limit) else {
+            return;
+        };
+        let Ok(mut files) = vector_files(directory) else {
+            return;
+        };
+        files.sort_by_key(|file| file.modified);
+        let mut usage: u64 = files.iter().map(|file| file.size).sum();
+        let target = limit / 10 * 9;
+        for file in files {
+            if usage <= target {
+                break;
+            }
+            if fs::remove_file(&file.path).is_ok() {
+                usage -= file.size;
+            }
+        }
+        self.disk_usage.store(usage, Ordering::Relaxed);
+    }
+
+    /// Looks `texts` up in memory, then on disk, and embeds the rest with
+    /// `embed`, once per distinct key.
+    fn cached<Error>(
+        &self,
+        texts: &[&str],
+        mode: &str,
+        embed: impl FnOnce(&[&str]) -> Result<Vec<Vec<f64>>, Error>,
+    ) -> Result<Vec<Vec<f64>>, Error> {
+        let keys: Vec<String> = texts.iter().map(|text| self.key(text, mode)).collect();
+        let mut embeddings: Vec<Option<Vec<f64>>> = vec![None; texts.len()];
+
+        {
+            let mut state = self.state.lock().unwrap();
+            for (i, key) in keys.iter().enumerate() {
+                if let Some(embedding) = state.memory.get(key) {
+                    embeddings[i] = Some(embedding);
+                    state.stats.hits += 1;
+                }
+            }
+        }
+
+        // Texts missing from memory: try the disk, then embed the rest once
+        // per distinct key.
+        let mut missing: Vec<&str> = vec![];
+        let mut missing_keys: Vec<&str> = vec![];
+        let mut missing_index: HashMap<&str, usize> = HashMap::new();
+        let mut disk_hits = vec![];
+        for (i, key) in keys.iter().enumerate() {
+            if embeddings[i].is_some() {
+                continue;
+            }
+            if let Some(embedding) = self.read_disk(key) {
+                disk_hits.push((key.clone(), embedding.clone()));
+                embeddings[i] = Some(embedding);
+            } else if !missing_index.contains_key(key.as_str()) {
+                missing_index.insert(key, missing.len());
+                missing_keys.push(key);
+                missing.push(texts[i]);
+            }
+        }
+        let computed = if missing.is_empty() {
+            vec![]
+        } else {
+            embed(&missing)?
+        };
+
+        let mut state = self.state.lock().unwrap();
+        state.stats.disk_hits += disk_hits.len() as u64;
+        state.stats.misses += missing.len() as u64;
+        for (key, embedding) in disk_hits {
+            state.stats.evictions += state.memory.insert(key, embedding);
+        }
+        for (key, embedding) in missing_keys.iter().zip(&computed) {
+            state.stats.evictions += state.memory.insert(key.to_string(), embedding.clone());
+        }
+        drop(state);
+        for (key, embedding) in missing_keys.iter().zip(&computed) {
+            self.write_disk(key, embedding);
+        }
+
+        Ok(embeddings
+            .into_iter()
+            .zip(&keys)
+            .map(|(embedding, key)| {
+                embedding.unwrap_or_else(|| computed[missing_index[key.as_str()]].clone())
+            })
+            .collect())
+    }
+}
+
+impl<E: Embedder> Embedder for CachedEmbedder<E> {
+    fn embed_batch(&self, texts: &[&str]) -> Vec<Vec<f64>> {
+        let Ok(embeddings) =
+            self.cached::<Infallible>(texts, "", |texts| Ok(self.inner.embed_batch(texts)));
+        embeddings
+    }
+
+    fn embed_windowed(
+        &self,
+        texts: &[&str],
+        window: &SlidingWindow,
+    ) -> Result<Vec<Vec<f64>>, String> {
+        let mode = format!("window:{}:{:?}", window.overlap, window.aggregation);
+        self.cached(texts, &mode, |texts| {
+            self.inner.embed_windowed(texts, window)
+        })
+    }
+
+    fn dimension(&self) -> usize {
+        self.inner.dimension()
+    }
+
+    fn model_id(&self) -> String {
+        self.model_id.clone()
+    }
+
+    fn embed_tokens(&self, texts: &[&str]) -> Vec<Vec<Vec<f64>>> {
+        self.inner.embed_tokens(texts)
+    }
+
+    fn token_offsets(&self, text: &str) -> Vec<(usize, usize)> {
+        self.inner.token_offsets(text)
+    }
+
+    fn embed_token_spans(&self, texts: &[&str]) -> Vec<Vec<TokenEmbedding>> {
+        self.inner.embed_token_spans(texts)
+    }
+}
+
+/// Least recently used map of cache keys to vectors. Each entry remembers
+/// the tick of its last use; `order` maps ticks back to keys so the oldest
+/// entry is found in O(log n).
+#[derive(Default)]
+struct Lru {
+    capacity: usize,
+    entries: HashMap<String, (Vec<f64>, u64)>,
+    order: BTreeMap<u64, String>,
+    tick: u64,
+}
+
+impl Lru {
+    fn new(capacity: usize) -> Lru {
+        Lru {
+            capacity,
+            ..Lru::default()
+        }
+    }
+
+    fn get(&mut self, key: &str) -> Option<Vec<f64>> {
+        let tick = self.next_tick();
+        let (embedding, last_used) = self.entries.get_mut(key)?;
+        self.order.remove(last_used);
+        *last_used = tick;
+        self.order.insert(tick, key.to_string());
+        Some(embedding.clone())
+    }
+
+    /// Inserts `embedding` and returns the number of evicted entries.
+    fn insert(&mut self, key: String, embedding: Vec<f64>) -> u64 {
+        if self.capacity == 0 {
+            return 0;
+        }
+        let tick = self.next_tick();
+        if let Some((_, last_used)) = self.entries.insert(key.clone(), (embedding, tick)) {
+            self.order.remove(&last_used);
+        }
+        self.order.insert(tick, key);
+
+        let mut evicted = 0;
+        while self.entries.len() > self.capacity {
+            let Some((_, oldest)) = self.order.pop_first() else {
+                break;
+            };
+            self.entries.remove(&oldest);
+            evicted += 1;
+        }
+        evicted
+    }
+
+    fn clear(&mut self) {
+        self.entries.clear();
+        self.order.clear();
+    }
+
+    fn next_tick(&mut self) -> u64 {
+        self.tick += 1;
+        self.tick
+    }
+}
+
+struct VectorFile {
+    path: PathBuf,
+    size: u64,
+    modified: SystemTime,
+}
+
+/// The vector files in the shard directories of a disk tier.
+fn vector_files(directory: &Path) -> io::Result<Vec<VectorFile>> {
+    let mut files = vec![];
+    for shard in fs::read_dir(directory)? {
+        let shard = shard?.path();
+        if !shard.is_dir() {
+            continue;
+        }
+        for entry in fs::read_dir(&shard)? {
+            let entry = entry?;
+            let path = entry.path();
+            if path.extension().and_then(|extension| extension.to_str()) != Some(VECTOR_EXTENSION) {
+                continue;
+            }
+            let metadata = entry.metadata()?;
+            files.push(VectorFile {
+                path,
+                size: metadata.len(),
+                modified: metadata.modified()?,
+            });
+        }
+    }
+    Ok(files)
+}
+
+fn clear_directory(directory: &Path) -> io::Result<()> {
+    for entry in fs::read_dir(directory)? {
+        let path = entry?.path();
+        if path.is_dir() {
+            fs::remove_dir_all(path)?;
+        } else {
+            fs::remove_file(path)?;
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::embeddings::HashingEmbedder;
+
+    fn temp_dir(name: &str) -> PathBuf {
+        let directory =
+            std::env::temp_dir().join(format!("vdb-cache-{}-{}", name, std::process::id()));
+        let _ = fs::remove_dir_all(&directory);
+        directory
+    }
+
+    #[test]
+    fn caches_in_memory_and_on_disk() {
+        let directory = temp_dir("tiers");
+        let cache = CachedEmbedder::new(HashingEmbedder::new(16), 2)
+            .with_disk(&directory)
+            .unwrap();
+        let first = cache.embed_batch(&["a b", "a  b", "c", "d"]);
+        assert_eq!(first[0], first[1]);
+        let stats = cache.stats();
+        assert_eq!((stats.hits, stats.misses, stats.evictions), (0, 3, 1));
+
+        let second = cache.embed_batch(&["a b", "d"]);
+        assert_eq!(second[0], first[0]);
+        let stats = cache.stats();
+        assert_eq!((stats.hits, stats.disk_hits, stats.misses), (1, 1, 3));
+        fs::remove_dir_all(&directory).unwrap();
+    }
+
+    #[test]
+    fn keeps_windowed_vectors_apart() {
+        let cache = CachedEmbedder::new(HashingEmbedder::new(16), 10);
+        let window = SlidingWindow::default();
+        cache.embed("a b");
+        cache.embed_windowed(&["a b"], &window).unwrap();
+        assert_eq!(cache.stats().misses, 2);
+        cache.embed_windowed(&["a b"], &window).unwrap();
+        assert_eq!(cache.stats().hits, 1);
+    }
+
+    #[test]
+    fn ignores_disk_vectors_of_the_wrong_size() {
+        let directory = temp_dir("size");
+        let cache = CachedEmbedder::new(HashingEmbedder::new(16), 0)
+            .with_disk(&directory)
+            .unwrap();
+        cache.embed("a b");
+        let path = cache.disk_path(&cache.key("a b", "")).unwrap();
+        assert_eq!(fs::read(&path).unwrap().len(), 16 * 8);
+
+        fs::write(&path, [0; 8 * 8]).unwrap();
+        cache.embed("a b");
+        assert_eq!(cache.stats().disk_hits, 0);
+        assert_eq!(cache.stats().misses, 2);
+        fs::remove_dir_all(&directory).unwrap();
+    }
+
+    #[test]
+    fn prunes_the_disk_tier_to_its_limit() {
+        let directory = temp_dir("limit");
+        let cache = CachedEmbedder::new(HashingEmbedder::new(16), 0)
+            .with_disk(&directory)
+            .unwrap()
+            .with_disk_limit(4 * 16 * 8);
+        let texts: Vec<String> = (0..10).map(|i| format!("text {}", i)).collect();
+        for text in &texts {
+            cache.embed(text);
+        }
+        let usage: u64 = vector_files(&directory)
+            .unwrap()
+            .iter()
+            .map(|file| file.size)
+            .sum();
+        assert!(usage <= 4 * 16 * 8);
+        assert_eq!(cache.disk_usage.load(Ordering::Relaxed), usage);
+        fs::remove_dir_all(&directory).unwrap();
+    }
+}
@@ -0,0 +1,251 @@
+use serde::Deserialize;
+use serde::de::DeserializeOwned;
+use std::collections::{BTreeSet, HashMap};
+use std::fs::File;
+use std::io::BufReader;
+use std::path::{Path, PathBuf};
+use tch::nn::VarStore;
+use tch::{Device, TchError, Tensor, no_grad};
+
+/// Weights converted for rust-bert, loaded as is.
+pub const RUST_WEIGHTS_FILE: &str = "rust_model.ot";
+pub const SAFETENSORS_FILE: &str = "model.safetensors";
+/// Index of a checkpoint split over several safetensors files.
+pub const SAFETENSORS_INDEX_FILE: &str = "model.safetensors.index.json";
+pub const PYTORCH_WEIGHTS_FILE: &str = "pytorch_model.bin";
+
+#[derive(Deserialize)]
+struct SafetensorsIndex {
+    weight_map: HashMap<String, String>,
+}
+
+/// The weights file `load_weights` reads from `directory`, in order of
+/// preference: `rust_model.ot`, `model.safetensors`, the sharded
+/// safetensors index, then `pytorch_model.bin`.
+pub fn weights_file(directory: &Path) -> Option<PathBuf> {
+    [
+        RUST_WEIGHTS_FILE,
+        SAFETENSORS_FILE,
+        SAFETENSORS_INDEX_FILE,
+        PYTORCH_WEIGHTS_FILE,
+    ]
+    .iter()
+    .map(|name| directory.join(name))
+    .find(|path| path.is_file())
+}
+
+/// Loads every variable of `vs` from the checkpoint in `directory`.
+///
+/// Hugging Face names go through `remap_names`, with `prefix` the path
+/// rust-bert nests the encoder under (e.g. `bert`); `rust_model.ot` files
+/// already use rust-bert names. Tensors are converted to the dtype of the
+/// model, so half precision checkpoints load into a float model. Extra
+/// tensors such as `position_ids` buffers or pretraining heads are ignored.
+pub fn load_weights(
+    vs: &mut VarStore,
+    directory: &Path,
+    prefix: Option<&str>,
+) -> Result<(), TchError> {
+    let path = weights_file(directory).ok_or_else(|| {
+        TchError::FileFormat(format!(
+            "no {}, {}, {} or {} in {}",
+            RUST_WEIGHTS_FILE,
+            SAFETENSORS_FILE,
+            SAFETENSORS_INDEX_FILE,
+            PYTORCH_WEIGHTS_FILE,
+            directory.display()
+        ))
+    })?;
+    let tensors: HashMap<String, Tensor> = remap_names(read_tensors(&path, Device::Cpu)?, prefix)
+        .into_iter()
+        .collect();
+    for (name, mut variable) in vs.variables() {
+        let source = tensors.get(&name).ok_or_else(|| {
+            TchError::TensorNameNotFound(name.clone(), path.display().to_string())
+        })?;
+        if source.size() != variable.size() {
+            return Err(TchError::Shape(format!(
+                "{} has shape {:?} in {} but the model expects {:?}",
+                name,
+                source.size(),
+                path.display(),
+                variable.size()
+            )));
+        }
+        no_grad(|| {
+            variable.f_copy_(&source.to_device(variable.device()).to_kind(variable.kind()))
+        })?;
+    }
+    Ok(())
+}
+
+/// Reads every named tensor of a checkpoint: a safetensors file, sharded
+/// safetensors index, numpy `.npz` or libtorch `.ot` file.
+///
+/// PyTorch `.bin`/`.pt` files go through libtorch's loader like `.ot`
+/// files. Those written by `torch.save` are usually pickles, which libtorch
+/// cannot read outside of Python; the error then explains how to convert
+/// them to safetensors.
+pub fn read_tensors(path: &Path, device: Device) -> Result<Vec<(String, Tensor)>, TchError> {
+    if path.ends_with(SAFETENSORS_INDEX_FILE) {
+        let index: SafetensorsIndex = read_json(path)?;
+        let directory = path.parent().unwrap_or(Path::new("."));
+        let shards: BTreeSet<&String> = index.weight_map.values().collect();
+        let mut tensors = vec![];
+        for shard in shards {
+            tensors.extend(Tensor::read_safetensors(directory.join(shard))?);
+        }
+        return Ok(tensors);
+    }
+    match path.extension().and_then(|extension| extension.to_str()) {
+        Some("safetensors") => Tensor::read_safetensors(path),
+        Some("bin") | Some("pt") => Tensor::load_multi_with_device(path, device).map_err(|e| {
+            TchError::FileFormat(format!(
+                "could not read {} ({}); if it is a PyTorch pickle, convert it to \
+                 safetensors first, e.g. with `python -c \"import torch, safetensors.torch \
+                 as st; st.save_file(torch.load('{}'), 'model.safetensors')\"`",
+                path.display(),
+                e,
+                path.display()
+            ))
+        }),
+        Some("npz") => Tensor::read_npz(path),
+        _ => Tensor::load_multi_with_device(path, device),
+    }
+}
+
+/// Parses a JSON file, reporting a missing or malformed file as an error
+/// rather than panicking like `Config::from_file`.
+pub fn read_json<T: DeserializeOwned>(path: &Path) -> Result<T, TchError> {
+    let file = File::open(path)
+        .map_err(|e| TchError::FileFormat(format!("could not open {}: {}", path.display(), e)))?;
+    serde_json::from_reader(BufReader::new(file))
+        .map_err(|e| TchError::FileFormat(format!("invalid {}: {}", path.display(), e)))
+}
+
+/// Writes `tensors` in the format given by the extension of `path`:
+/// safetensors, numpy `.npz` or, for anything else, libtorch `.ot`.
+pub fn write_tensors(path: &Path, tensors: &[(String, Tensor)]) -> Result<(), TchError> {
+    match path.extension().and_then(|extension| extension.to_str()) {
+        Some("safetensors") => Tensor::write_safetensors(tensors, path),
+        Some("npz") => Tensor::write_npz(tensors, path),
+        Some("bin") | Some("pt") => Err(TchError::FileFormat(format!(
+            "cannot write PyTorch pickles ({})",
+            path.display()
+        ))),
+        _ => Tensor::save_multi(tensors, path),
+    }
+}
+
+/// Renames Hugging Face parameters to the names rust-bert registers.
+///
+/// Legacy LayerNorm `gamma`/`beta` become `weight`/`bias`. When no name
+/// starts with `prefix`, the checkpoint holds a bare encoder and every name
+/// gains the prefix; full models (e.g. with a classification head) already
+/// use the rust-bert layout and keep their names.
+pub fn remap_names(tensors: Vec<(String, Tensor)>, prefix: Option<&str>) -> Vec<(String, Tensor)> {
+    let (names, tensors): (Vec<String>, Vec<Tensor>) = tensors.into_iter().unzip();
+    remapped(names, prefix).into_iter().zip(tensors).collect()
+}
+
+/// The names `remap_names` gives to tensors named `names`.
+fn remapped(names: Vec<String>, prefix: Option<&str>) -> Vec<String> {
+    let prefix = prefix.map(|prefix| format!("{}.", prefix));
+    let bare = prefix
+        .as_ref()
+        .is_some_and(|prefix| !names.iter().any(|name| name.starts_with(prefix)));
+
+    names
+        .into_iter()
+        .map(|name| {
+            let name = match name.rsplit_once('.') {
+                Some((module, "gamma")) => format!("{}.weight", module),
+                Some((module, "beta")) => format!("{}.bias", module),
+                _ => name,
+            };
+            match &prefix {
+                Some(prefix) if bare => format!("{}{}", prefix, name),
+                _ => name,
+            }
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn remapped_names(names: &[&str], prefix: Option<&str>) -> Vec<String> {
+        remapped(names.iter().map(|name| name.to_string()).collect(), prefix)
+    }
+
+    #[test]
+    fn bare_encoders_gain_the_rust_bert_prefix() {
+        assert_eq!(
+            remapped_names(
+                &[
+                    "embeddings.word_embeddings.weight",
+                    "embeddings.LayerNorm.gamma",
+                    "encoder.layer.0.output.LayerNorm.beta",
+                    "pooler.dense.weight",
+                ],
+                Some("bert"),
+            ),
+            [
+                "bert.embeddings.word_embeddings.weight",
+                "bert.embeddings.LayerNorm.weight",
+                "bert.encoder.layer.0.output.LayerNorm.bias",
+                "bert.pooler.dense.weight",
+            ]
+        );
+        assert_eq!(
+            remapped_names(
+                &[
+                    "embeddings.word_embeddings.weight",
+                    "transformer.layer.0.attention.q_lin.weight",
+                ],
+                Some("distilbert"),
+            ),
+            [
+                "distilbert.embeddings.word_embeddings.weight",
+                "distilbert.transformer.layer.0.attention.q_lin.weight",
+            ]
+        );
+        assert_eq!(
+            remapped_names(
+                &[
+                    "encoder.relative_attention_bias.weight",
+                    "encoder.layer.0.attention.attn.q.bias",
+                ],
+                Some("mpnet"),
+            ),
+            [
+                "mpnet.encoder.relative_attention_bias.weight",
+                "mpnet.encoder.layer.0.attention.attn.q.bias",
+            ]
+        );
+    }
+
+    #[test]
+    fn prefixed_checkpoints_keep_their_names() {
+        assert_eq!(
+            remapped_names(
+                &[
+                    "bert.embeddings.LayerNorm.gamma",
+                    "bert.encoder.layer.0.attention.self.query.weight",
+                    "classifier.weight",
+                ],
+                Some("bert"),
+            ),
+            [
+                "bert.embeddings.LayerNorm.weight",
+                "bert.encoder.layer.0.attention.self.query.weight",
+                "classifier.weight",
+            ]
+        );
+        assert_eq!(
+            remapped_names(&["linear.weight", "linear.bias"], None),
+            ["linear.weight", "linear.bias"]
+        );
+    }
+}
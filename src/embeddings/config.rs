@@ -0,0 +1,135 @@
+use crate::embeddings::DEFAULT_MODEL_PATH;
+use rust_bert::Config;
+use serde::{Deserialize, Serialize};
+use std::env;
+use std::fs::File;
+use std::io::BufReader;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use tch::{Device, TchError};
+
+/// Environment variable naming a JSON file to read an `EmbeddingConfig` from.
+pub const CONFIG_FILE_ENV: &str = "VDB_EMBEDDING_CONFIG";
+
+/// Where and how the sentence embedding model is loaded.
+///
+/// Read from a JSON file with `EmbeddingConfig::from_json_file`, or from the
+/// environment with `EmbeddingConfig::from_env`. Every field may be omitted.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct EmbeddingConfig {
+    /// Sentence-transformers checkpoint directory.
+    pub model_dir: PathBuf,
+    /// `auto`, `cpu`, `cuda`, `cuda:<index>` or `mps`. `auto` picks the first
+    /// CUDA device when one is available.
+    pub device: String,
+    /// Defaults to the checkpoint's `sentence_bert_config.json`, then 128.
+    pub max_seq_length: Option<i64>,
+    /// Defaults to the checkpoint's `sentence_bert_config.json`, then its
+    /// `tokenizer_config.json`, then the architecture's convention.
+    pub do_lower_case: Option<bool>,
+    /// Number of intra-op threads used by torch on the CPU.
+    pub num_threads: Option<i32>,
+    /// Number of embeddings kept in memory by the database's
+    /// `CachedEmbedder`; 0 disables the cache.
+    pub cache_capacity: usize,
+    /// Directory persisting cached embeddings across runs.
+    pub cache_dir: Option<PathBuf>,
+    /// Bytes the `cache_dir` may grow to before its oldest vectors are
+    /// deleted; unlimited if unset.
+    pub cache_disk_limit: Option<u64>,
+}
+
+impl Config for EmbeddingConfig {}
+
+impl Default for EmbeddingConfig {
+    fn default() -> Self {
+        EmbeddingConfig {
+            model_dir: PathBuf::from(DEFAULT_MODEL_PATH),
+            device: "auto".to_string(),
+            max_seq_length: None,
+            do_lower_case: None,
+            num_threads: None,
+            cache_capacity: 10_000,
+            cache_dir: None,
+            cache_disk_limit: None,
+        }
+    }
+}
+
+impl EmbeddingConfig {
+    /// Starts from the file named by `VDB_EMBEDDING_CONFIG`, if set, and
+    /// applies `VDB_MODEL_DIR`, `VDB_DEVICE`, `VDB_MAX_SEQ_LENGTH`,
+    /// `VDB_DO_LOWER_CASE`, `VDB_NUM_THREADS`, `VDB_CACHE_CAPACITY`,
+    /// `VDB_CACHE_DIR` and `VDB_CACHE_DISK_LIMIT` on top of it.
+    pub fn from_env() -> Result<EmbeddingConfig, String> {
+        let mut config = match env::var(CONFIG_FILE_ENV) {
+            Ok(path) => EmbeddingConfig::from_json_file(path)?,
+            Err(_) => EmbeddingConfig::default(),
+        };
+        if let Ok(model_dir) = env::var("VDB_MODEL_DIR") {
+            config.model_dir = PathBuf::from(model_dir);
+        }
+        if let Ok(device) = env::var("VDB_DEVICE") {
+            config.device = device;
+        }
+        if let Some(value) = parse_env("VDB_MAX_SEQ_LENGTH")? {
+            config.max_seq_length = Some(value);
+        }
+        if let Some(value) = parse_env("VDB_DO_LOWER_CASE")? {
+            config.do_lower_case = Some(value);
+        }
+        if let Some(value) = parse_env("VDB_NUM_THREADS")? {
+            config.num_threads = Some(value);
+        }
+        if let Some(value) = parse_env("VDB_CACHE_CAPACITY")? {
+            config.cache_capacity = value;
+        }
+        if let Ok(cache_dir) = env::var("VDB_CACHE_DIR") {
+            config.cache_dir = Some(PathBuf::from(cache_dir));
+        }
+        if let Some(value) = parse_env("VDB_CACHE_DISK_LIMIT")? {
+            config.cache_disk_limit = Some(value);
+        }
+        config.device()?;
+        Ok(config)
+    }
+
+    /// Reads a config from a JSON file. Unlike `Config::from_file`, a
+    /// missing or malformed file is an error rather than a panic.
+    pub fn from_json_file<P: AsRef<Path>>(path: P) -> Result<EmbeddingConfig, String> {
+        let path = path.as_ref();
+        let file = File::open(path)
+            .map_err(|e| format!("Could not open config file {}: {}", path.display(), e))?;
+        serde_json::from_reader(BufReader::new(file))
+            .map_err(|e| format!("Invalid config file {}: {}", path.display(), e))
+    }
+
+    pub fn device(&self) -> Result<Device, String> {
+        match self.device.to_lowercase().as_str() {
+            "auto" => Ok(Device::cuda_if_available()),
+            "cpu" => Ok(Device::Cpu),
+            "cuda" => Ok(Device::Cuda(0)),
+            "mps" => Ok(Device::Mps),
+            other => other
+                .strip_prefix("cuda:")
+                .and_then(|index| index.parse().ok())
+                .map(Device::Cuda)
+                .ok_or_else(|| format!("Unsupported device: {}", self.device)),
+        }
+    }
+
+    pub(crate) fn tch_device(&self) -> Result<Device, TchError> {
+        self.device().map_err(TchError::Convert)
+    }
+}
+
+fn parse_env<T: FromStr>(name: &str) -> Result<Option<T>, String> {
+    match env::var(name) {
+        Ok(value) => value
+            .parse()
+            .map(Some)
+            .map_err(|_| format!("Invalid value for {}: {}", name, value)),
+        Err(_) => Ok(None),
+    }
+}
@@ -0,0 +1,159 @@
+use std::path::Path;
+
+use rust_bert::bert::{BertConfig, BertForSequenceClassification};
+use rust_tokenizers::tokenizer::{BertTokenizer, MultiThreadedTokenizer, Tokenizer};
+use rust_tokenizers::vocab::Vocab;
+use tch::nn::VarStore;
+use tch::{Device, Kind, TchError, Tensor, nn, no_grad};
+
+use crate::embeddings::EMBEDDING_BATCH_SIZE;
+use crate::embeddings::bert::{pair_features, resolve_lower_case, truncate_pair};
+use crate::embeddings::checkpoint::{load_weights, read_json};
+
+/// Scores how relevant each passage is to a query, higher is better.
+///
+/// Like `Embedder`, implementations only need to be `Send`; databases keep
+/// their reranker behind a lock.
+pub trait Reranker: Send {
+    fn score(&self, query: &str, passages: &[&str]) -> Vec<f64>;
+}
+
+/// BERT with a classification head that reads a (query, passage) pair as a
+/// single input, e.g. `cross-encoder/ms-marco-MiniLM-L-6-v2`. Much slower
+/// than comparing embeddings, so it is meant for re-ranking a short list of
+/// candidates.
+pub struct CrossEncoder {
+    model: BertForSequenceClassification,
+    tokenizer: BertTokenizer,
+    max_seq_length: i64,
+    num_labels: i64,
+    cls_token_id: i64,
+    sep_token_id: i64,
+    pad_token_id: i64,
+    pub vs: VarStore,
+}
+
+impl CrossEncoder {
+    /// Loads `config.json`, `vocab.txt` and the weights (see `load_weights`)
+    /// from `model_path`, with the weights under the same `bert` prefix
+    /// `Bert` uses plus the `classifier` head.
+    pub fn new(
+        model_path: &Path,
+        max_seq_length: Option<i64>,
+        do_lower_case: Option<bool>,
+        device: Device,
+    ) -> Result<CrossEncoder, TchError> {
+        let max_seq_length = max_seq_length.unwrap_or(510).min(510);
+
+        let mut vs = nn::VarStore::new(device);
+        let config: BertConfig = read_json(&model_path.join("config.json"))?;
+        let num_labels = config
+            .id2label
+            .as_ref()
+            .map_or(1, |labels| labels.len() as i64);
+        let model = BertForSequenceClassification::new(vs.root(), &config)
+            .map_err(|e| TchError::FileFormat(e.to_string()))?;
+
+        let do_lower_case = resolve_lower_case(model_path, do_lower_case, true)?;
+        let tokenizer =
+            BertTokenizer::from_file(model_path.join("vocab.txt"), do_lower_case, do_lower_case)
+                .map_err(|e| TchError::FileFormat(e.to_string()))?;
+        let vocab = Tokenizer::vocab(&tokenizer);
+        let cls_token_id = vocab.token_to_id("[CLS]");
+        let sep_token_id = vocab.token_to_id("[SEP]");
+        let pad_token_id = vocab.token_to_id("[PAD]");
+
+        load_weights(&mut vs, model_path, Some("bert"))?;
+
+        Ok(CrossEncoder {
+            model,
+            tokenizer,
+            max_seq_length,
+            num_labels,
+            cls_token_id,
+            sep_token_id,
+            pad_token_id,
+            vs,
+        })
+    }
+
+    /// Relevance of each passage to `query` in `[0, 1]`: the sigmoid of the
+    /// logit for single-output heads, otherwise the softmax probability of
+    /// the last label.
+    pub fn predict(&self, query: &str, passages: &[&str]) -> Vec<f64> {
+        if passages.is_empty() {
+            return vec![];
+        }
+
+        let query_tokens = self
+            .tokenizer
+            .convert_tokens_to_ids(&self.tokenizer.tokenize(query));
+        let passage_tokens: Vec<Vec<i64>> =
+            MultiThreadedTokenizer::tokenize_list(&self.tokenizer, passages)
+                .iter()
+                .map(|tokens| self.tokenizer.convert_tokens_to_ids(tokens))
+                .collect();
+
+        let max_tokens = (self.max_seq_length as usize).saturating_sub(1);
+        let pairs: Vec<(&[i64], &[i64])> = passage_tokens
+            .iter()
+            .map(|passage| truncate_pair(&query_tokens, passage, max_tokens))
+            .collect();
+        let pad_length = pairs
+            .iter()
+            .map(|(first, second)| first.len() + second.len() + 3)
+            .max()
+            .unwrap_or(0);
+
+        let mut ids = Vec::with_capacity(pairs.len());
+        let mut types = Vec::with_capacity(pairs.len());
+        let mut masks = Vec::with_capacity(pairs.len());
+        for (first, second) in pairs {
+            let (input_ids, token_type_ids, input_mask) = pair_features(
+                first,
+                second,
+                pad_length,
+                (self.cls_token_id, self.sep_token_id, self.pad_token_id),
+            );
+            ids.push(Tensor::from_slice(&input_ids));
+            types.push(Tensor::from_slice(&token_type_ids));
+            masks.push(Tensor::from_slice(&input_mask));
+        }
+
+        let device = self.vs.device();
+        let input_ids = Tensor::stack(&ids, 0).to(device);
+        let token_type_ids = Tensor::stack(&types, 0).to(device);
+        let input_mask = Tensor::stack(&masks, 0).to(device);
+
+        let logits = no_grad(|| {
+            self.model
+                .forward_t(
+                    Some(&input_ids),
+                    Some(&input_mask),
+                    Some(&token_type_ids),
+                    None,
+                    None,
+                    false,
+                )
+                .logits
+        });
+        let scores = if self.num_labels == 1 {
+            logits.squeeze_dim(1).sigmoid()
+        } else {
+            logits
+                .softmax(1, Kind::Float)
+                .select(1, self.num_labels - 1)
+        };
+        Vec::<f64>::try_from(scores.to_kind(Kind::Double).to(Device::Cpu)).unwrap()
+    }
+}
+
+impl Reranker for CrossEncoder {
+    fn score(&self, query: &str, passages: &[&str]) -> Vec<f64> {
+        let mut scores = Vec::with_capacity(passages.len());
+        for batch in passages.chunks(EMBEDDING_BATCH_SIZE) {
+            scores.extend(self.predict(query, batch));
+        }
+        scores
+    }
+}
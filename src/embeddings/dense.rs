@@ -0,0 +1,111 @@
+use crate::embeddings::Features;
+use crate::embeddings::checkpoint::{load_weights, read_json};
+use rust_bert::Config;
+use serde::{Deserialize, Serialize};
+use std::path::Path;
+use tch::nn::{Module, VarStore};
+use tch::{Device, TchError, Tensor, nn};
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct DenseConfig {
+    pub in_features: i64,
+    pub out_features: i64,
+    pub bias: Option<bool>,
+    pub activation_function: Option<String>,
+}
+
+impl Config for DenseConfig {}
+
+/// Activations sentence-transformers writes into a Dense `config.json`,
+/// named by their torch class path.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Activation {
+    Identity,
+    Tanh,
+    ReLU,
+    Sigmoid,
+    Gelu,
+}
+
+impl Activation {
+    pub fn from_name(name: &str) -> Result<Activation, TchError> {
+        match name.rsplit('.').next().unwrap_or(name) {
+            "Identity" => Ok(Activation::Identity),
+            "Tanh" => Ok(Activation::Tanh),
+            "ReLU" => Ok(Activation::ReLU),
+            "Sigmoid" => Ok(Activation::Sigmoid),
+            "GELU" => Ok(Activation::Gelu),
+            _ => Err(TchError::FileFormat(format!(
+                "unsupported Dense activation `{}`",
+                name
+            ))),
+        }
+    }
+
+    fn apply(&self, x: &Tensor) -> Tensor {
+        match self {
+            Activation::Identity => x.shallow_clone(),
+            Activation::Tanh => x.tanh(),
+            Activation::ReLU => x.relu(),
+            Activation::Sigmoid => x.sigmoid(),
+            Activation::Gelu => x.gelu("none"),
+        }
+    }
+}
+
+/// Linear projection of the sentence embedding followed by an activation,
+/// e.g. the `2_Dense` module of `distiluse-base-multilingual-cased`.
+///
+/// The weights live in the module's own directory, so it keeps its own
+/// `VarStore` rather than sharing the transformer's.
+pub struct Dense {
+    linear: nn::Linear,
+    activation: Activation,
+    out_features: i64,
+    pub vs: VarStore,
+}
+
+impl Dense {
+    pub fn new(module_path: &Path, device: Device) -> Result<Dense, TchError> {
+        let config: DenseConfig = read_json(&module_path.join("config.json"))?;
+        let activation = match &config.activation_function {
+            Some(name) => Activation::from_name(name)?,
+            None => Activation::Tanh,
+        };
+
+        let mut vs = VarStore::new(device);
+        let linear = nn::linear(
+            &vs.root() / "linear",
+            config.in_features,
+            config.out_features,
+            nn::LinearConfig {
+                bias: config.bias.unwrap_or(true),
+                ..Default::default()
+            },
+        );
+        load_weights(&mut vs, module_path, None)?;
+
+        Ok(Dense {
+            linear,
+            activation,
+            out_features: config.out_features,
+            vs,
+        })
+    }
+
+    pub fn output_dimension(&self) -> usize {
+        self.out_features as usize
+    }
+
+    pub fn forward_t(&self, features: Features) -> Features {
+        let sentence_embedding = features.sentence_embedding.as_ref().unwrap();
+        let projected = self
+            .activation
+            .apply(&self.linear.forward(sentence_embedding));
+
+        Features {
+            sentence_embedding: Some(projected),
+            ..features
+        }
+    }
+}
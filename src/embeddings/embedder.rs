@@ -0,0 +1,252 @@
+use crate::embeddings::{EMBEDDING_BATCH_SIZE, SentenceTransformer, SlidingWindow};
+use crate::util::word_spans;
+use std::sync::{Arc, Mutex};
+
+/// A text embedding model the database can be built on.
+///
+/// Implementations only need to be `Send`: databases keep their embedder
+/// behind a lock, so models holding tensors can be shared between threads.
+pub trait Embedder: Send {
+    fn embed(&self, text: &str) -> Vec<f64> {
+        self.embed_batch(&[text]).remove(0)
+    }
+
+    fn embed_batch(&self, texts: &[&str]) -> Vec<Vec<f64>>;
+
+    /// Embeds `texts` without truncating them: texts longer than the model's
+    /// sequence limit are embedded as overlapping windows whose vectors are
+    /// aggregated. Models without such a limit embed texts whole.
+    fn embed_windowed(
+        &self,
+        texts: &[&str],
+        _window: &SlidingWindow,
+    ) -> Result<Vec<Vec<f64>>, String> {
+        Ok(self.embed_batch(texts))
+    }
+
+    /// Length of the vectors returned by `embed`.
+    fn dimension(&self) -> usize;
+
+    /// Identifies the model, so vectors from different models are not mixed.
+    fn model_id(&self) -> String;
+
+    /// One vector per token of each text, for late-interaction (MaxSim)
+    /// scoring. Models without token-level output return the text embedding
+    /// as a single vector.
+    fn embed_tokens(&self, texts: &[&str]) -> Vec<Vec<Vec<f64>>> {
+        self.embed_batch(texts)
+            .into_iter()
+            .map(|embedding| vec![embedding])
+            .collect()
+    }
+
+    /// Byte ranges of the tokens the model splits `text` into, in order,
+    /// used to size chunks. Defaults to whitespace separated words.
+    fn token_offsets(&self, text: &str) -> Vec<(usize, usize)> {
+        word_spans(text)
+    }
+
+    /// Vectors of the tokens of each text that map back to a part of it,
+    /// for explaining matches; special tokens are left out. Models without
+    /// token-level output return the text embedding spanning the whole text.
+    fn embed_token_spans(&self, texts: &[&str]) -> Vec<Vec<TokenEmbedding>> {
+        self.embed_batch(texts)
+            .into_iter()
+            .zip(texts)
+            .map(|(embedding, text)| {
+                vec![TokenEmbedding {
+                    start: 0,
+                    end: text.len(),
+                    embedding,
+                }]
+            })
+            .collect()
+    }
+}
+
+/// The vector of one token and the byte range of the text it came from.
+#[derive(Debug, Clone)]
+pub struct TokenEmbedding {
+    pub start: usize,
+    pub end: usize,
+    pub embedding: Vec<f64>,
+}
+
+impl Embedder for SentenceTransformer {
+    fn embed(&self, text: &str) -> Vec<f64> {
+        self.encode(text)
+    }
+
+    fn embed_batch(&self, texts: &[&str]) -> Vec<Vec<f64>> {
+        let mut embeddings = Vec::with_capacity(texts.len());
+        for batch in texts.chunks(EMBEDDING_BATCH_SIZE) {
+            embeddings.extend(self.encode_batch(batch));
+        }
+        embeddings
+    }
+
+    fn embed_windowed(
+        &self,
+        texts: &[&str],
+        window: &SlidingWindow,
+    ) -> Result<Vec<Vec<f64>>, String> {
+        texts
+            .iter()
+            .map(|text| self.encode_windowed(text, window))
+            .collect()
+    }
+
+    fn dimension(&self) -> usize {
+        self.output_dimension()
+    }
+
+    fn model_id(&self) -> String {
+        self.model_id.clone()
+    }
+
+    fn embed_tokens(&self, texts: &[&str]) -> Vec<Vec<Vec<f64>>> {
+        let mut embeddings = Vec::with_capacity(texts.len());
+        for batch in texts.chunks(EMBEDDING_BATCH_SIZE) {
+            embeddings.extend(self.encode_token_embeddings(batch));
+        }
+        embeddings
+    }
+
+    fn token_offsets(&self, text: &str) -> Vec<(usize, usize)> {
+        let (_, spans) = self.bert.tokenize_with_spans(text);
+        spans.into_iter().flatten().collect()
+    }
+
+    fn embed_token_spans(&self, texts: &[&str]) -> Vec<Vec<TokenEmbedding>> {
+        let mut embeddings = Vec::with_capacity(texts.len());
+        for batch in texts.chunks(EMBEDDING_BATCH_SIZE) {
+            embeddings.extend(self.encode_token_spans(batch));
+        }
+        embeddings
+    }
+}
+
+/// A model shared with the caller, who can take it back with
+/// `Arc::into_inner` once the database is dropped.
+impl<E: Embedder> Embedder for Arc<Mutex<E>> {
+    fn embed(&self, text: &str) -> Vec<f64> {
+        self.lock().unwrap().embed(text)
+    }
+
+    fn embed_batch(&self, texts: &[&str]) -> Vec<Vec<f64>> {
+        self.lock().unwrap().embed_batch(texts)
+    }
+
+    fn embed_windowed(
+        &self,
+        texts: &[&str],
+        window: &SlidingWindow,
+    ) -> Result<Vec<Vec<f64>>, String> {
+        self.lock().unwrap().embed_windowed(texts, window)
+    }
+
+    fn dimension(&self) -> usize {
+        self.lock().unwrap().dimension()
+    }
+
+    fn model_id(&self) -> String {
+        self.lock().unwrap().model_id()
+    }
+
+    fn embed_tokens(&self, texts: &[&str]) -> Vec<Vec<Vec<f64>>> {
+        self.lock().unwrap().embed_tokens(texts)
+    }
+
+    fn token_offsets(&self, text: &str) -> Vec<(usize, usize)> {
+        self.lock().unwrap().token_offsets(text)
+    }
+
+    fn embed_token_spans(&self, texts: &[&str]) -> Vec<Vec<TokenEmbedding>> {
+        self.lock().unwrap().embed_token_spans(texts)
+    }
+}
+
+/// Deterministic bag-of-words embedder for tests and model-free setups.
+///
+/// Each lowercased whitespace token is hashed into one of `dimension`
+/// buckets with a hash-derived sign, and the result is L2 normalised, so
+/// texts sharing words score higher than unrelated texts.
+pub struct HashingEmbedder {
+    dimension: usize,
+}
+
+impl HashingEmbedder {
+    pub fn new(dimension: usize) -> HashingEmbedder {
+        assert!(dimension > 0, "HashingEmbedder needs a non-zero dimension");
+        HashingEmbedder { dimension }
+    }
+}
+
+impl Embedder for HashingEmbedder {
+    fn embed_batch(&self, texts: &[&str]) -> Vec<Vec<f64>> {
+        texts
+            .iter()
+            .map(|text| {
+                let mut embedding = vec![0.0; self.dimension];
+                for token in text.split_whitespace() {
+                    let hash = fnv1a(token.to_lowercase().as_bytes());
+                    let bucket = (hash % self.dimension as u64) as usize;
+                    let sign = if hash >> 63 == 0 { 1.0 } else { -1.0 };
+                    embedding[bucket] += sign;
+                }
+                let norm = embedding.iter().map(|x| x * x).sum::<f64>().sqrt();
+                if norm > 0.0 {
+                    embedding.iter_mut().for_each(|x| *x /= norm);
+                }
+                embedding
+            })
+            .collect()
+    }
+
+    fn dimension(&self) -> usize {
+        self.dimension
+    }
+
+    fn model_id(&self) -> String {
+        format!("hashing-{}", self.dimension)
+    }
+
+    /// Each whitespace token is embedded on its own.
+    fn embed_tokens(&self, texts: &[&str]) -> Vec<Vec<Vec<f64>>> {
+        texts
+            .iter()
+            .map(|text| {
+                let tokens: Vec<&str> = text.split_whitespace().collect();
+                self.embed_batch(&tokens)
+            })
+            .collect()
+    }
+
+    fn embed_token_spans(&self, texts: &[&str]) -> Vec<Vec<TokenEmbedding>> {
+        texts
+            .iter()
+            .map(|text| {
+                let spans = word_spans(text);
+                let tokens: Vec<&str> = spans
+                    .iter()
+                    .map(|&(start, end)| &text[start..end])
+                    .collect();
+                self.embed_batch(&tokens)
+                    .into_iter()
+                    .zip(spans)
+                    .map(|(embedding, (start, end))| TokenEmbedding {
+                        start,
+                        end,
+                        embedding,
+                    })
+                    .collect()
+            })
+            .collect()
+    }
+}
+
+fn fnv1a(bytes: &[u8]) -> u64 {
+    bytes.iter().fold(0xcbf29ce484222325, |hash, &byte| {
+        (hash ^ byte as u64).wrapping_mul(0x100000001b3)
+    })
+}
@@ -1,70 +1,310 @@
 use rust_bert::Config;
-use std::path::Path;
+use sha2::{Digest, Sha256};
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::time::UNIX_EPOCH;
 use tch::{Device, Kind, Tensor, no_grad};
 
 pub mod bert;
+pub mod cache;
+pub mod checkpoint;
+pub mod config;
+pub mod cross_encoder;
+pub mod dense;
+pub mod embedder;
+pub mod modules;
+pub mod mpnet;
 pub mod pooling;
+pub mod training;
+pub mod window;
 
 use bert::{Bert, Features};
-use pooling::{Pooling, PoolingConfig};
+pub use cache::{CacheStats, CachedEmbedder};
+pub use config::EmbeddingConfig;
+pub use cross_encoder::{CrossEncoder, Reranker};
+pub use embedder::{Embedder, HashingEmbedder, TokenEmbedding};
+use modules::{Module, ModulesConfig};
+pub use training::TrainingConfig;
+pub use window::{SlidingWindow, WindowAggregation};
+
+pub const EMBEDDING_BATCH_SIZE: usize = 64;
+
+pub const DEFAULT_MODEL_PATH: &str = "models/bert-base-nli-mean-tokens";
 
 pub struct SentenceTransformer {
     pub bert: Bert,
-    pub pooling: Pooling,
+    /// Stages run on the transformer output, as listed in `modules.json`.
+    pub modules: Vec<Module>,
+    model_id: String,
 }
 
 impl SentenceTransformer {
     pub fn new(model_path: &Path, device: Device) -> Result<SentenceTransformer, tch::TchError> {
-        let bert_model_path = model_path.join("0_BERT");
-        let pooling_config_path = model_path.join("1_Pooling/config.json");
+        SentenceTransformer::with_options(model_path, device, None, None)
+    }
+
+    /// Loads the model described by `config`, applying its thread count to
+    /// torch before anything runs.
+    pub fn from_config(config: &EmbeddingConfig) -> Result<SentenceTransformer, tch::TchError> {
+        if let Some(num_threads) = config.num_threads {
+            tch::set_num_threads(num_threads);
+        }
+        SentenceTransformer::with_options(
+            &config.model_dir,
+            config.tch_device()?,
+            config.max_seq_length,
+            config.do_lower_case,
+        )
+    }
 
-        let bert = Bert::new(&bert_model_path, None, None, device);
-        let pooling = Pooling::new(
-            &(&bert.vs.root() / "pooling"),
-            &PoolingConfig::from_file(&pooling_config_path),
+    fn with_options(
+        model_path: &Path,
+        device: Device,
+        max_seq_length: Option<i64>,
+        do_lower_case: Option<bool>,
+    ) -> Result<SentenceTransformer, tch::TchError> {
+        let modules_config = ModulesConfig::load(model_path)?;
+        let transformer_path = modules_config.transformer_path(model_path);
+        let bert = Bert::new(&transformer_path, max_seq_length, do_lower_case, device)?;
+        let modules = modules_config.build(model_path, &bert.vs.root(), device)?;
+        let model_id = model_id(
+            model_path,
+            &transformer_path,
+            &modules_config,
+            bert.max_seq_length(),
+            bert.do_lower_case(),
         );
 
-        Ok(SentenceTransformer { bert, pooling })
+        Ok(SentenceTransformer {
+            bert,
+            modules,
+            model_id,
+        })
+    }
+
+    /// Length of the sentence embedding produced by the last module that
+    /// sets one.
+    pub fn output_dimension(&self) -> usize {
+        self.modules
+            .iter()
+            .rev()
+            .find_map(Module::output_dimension)
+            .unwrap_or(0)
     }
 
     pub fn encode(&self, text: &str) -> Vec<f64> {
-        // 1) tokenize & build batched feature tensors
-        let tokens = self.bert.tokenize(text);
-        let max_len = tokens.len();
-        let (input_ids, token_type_ids, input_mask, _len) =
-            self.bert.get_sentence_features(&tokens, max_len);
+        self.encode_batch(&[text]).remove(0)
+    }
+
+    pub fn encode_batch(&self, texts: &[&str]) -> Vec<Vec<f64>> {
+        if texts.is_empty() {
+            return vec![];
+        }
+
+        let tokens = self.bert.tokenize_multithreaded(texts.to_vec());
+        let sent = self.encode_tokens(&tokens);
+        Vec::<Vec<f64>>::try_from(sent).unwrap()
+    }
+
+    /// Per-token output of the transformer for each text, without the
+    /// padding positions, as used for late-interaction scoring.
+    pub fn encode_token_embeddings(&self, texts: &[&str]) -> Vec<Vec<Vec<f64>>> {
+        if texts.is_empty() {
+            return vec![];
+        }
+
+        let tokens = self.bert.tokenize_multithreaded(texts.to_vec());
+        let features = no_grad(|| self.bert.forward_t(self.token_features(&tokens)));
+
+        let token_embeddings = features
+            .token_embeddings
+            .unwrap()
+            .to_kind(Kind::Double)
+            .to(Device::Cpu);
+        let lengths = features
+            .input_mask
+            .unwrap()
+            .sum_dim_intlist(1, false, Kind::Int64)
+            .to(Device::Cpu);
+        let token_embeddings = Vec::<Vec<Vec<f64>>>::try_from(token_embeddings).unwrap();
+        let lengths = Vec::<i64>::try_from(lengths).unwrap();
+        token_embeddings
+            .into_iter()
+            .zip(lengths)
+            .map(|(mut rows, length)| {
+                rows.truncate(length as usize);
+                rows
+            })
+            .collect()
+    }
+
+    /// Transformer output for each token of each text with the byte range of
+    /// the text it came from. Special tokens and tokens cut off at
+    /// `max_seq_length` are left out.
+    pub fn encode_token_spans(&self, texts: &[&str]) -> Vec<Vec<TokenEmbedding>> {
+        if texts.is_empty() {
+            return vec![];
+        }
+
+        let (tokens, spans): (Vec<Vec<i64>>, Vec<Vec<Option<(usize, usize)>>>) = texts
+            .iter()
+            .map(|text| self.bert.tokenize_with_spans(text))
+            .unzip();
+        let features = no_grad(|| self.bert.forward_t(self.token_features(&tokens)));
+        let token_embeddings = features
+            .token_embeddings
+            .unwrap()
+            .to_kind(Kind::Double)
+            .to(Device::Cpu);
+        let token_embeddings = Vec::<Vec<Vec<f64>>>::try_from(token_embeddings).unwrap();
+        token_embeddings
+            .into_iter()
+            .zip(spans)
+            .map(|(rows, spans)| {
+                // The first row is the [CLS] token.
+                spans
+                    .into_iter()
+                    .zip(rows.into_iter().skip(1))
+                    .take(self.bert.max_seq_length())
+                    .filter_map(|(span, embedding)| {
+                        let (start, end) = span?;
+                        Some(TokenEmbedding {
+                            start,
+                            end,
+                            embedding,
+                        })
+                    })
+                    .collect()
+            })
+            .collect()
+    }
+
+    /// Runs already tokenized texts through the transformer and modules as
+    /// one batch and returns the `[batch, dim]` sentence embeddings on the CPU.
+    pub(crate) fn encode_tokens<T: AsRef<[i64]>>(&self, tokens: &[T]) -> Tensor {
+        let features = self.token_features(tokens);
 
-        let mut ids = Vec::with_capacity(1);
-        let mut types = Vec::with_capacity(1);
-        let mut masks = Vec::with_capacity(1);
+        // 2) forward passes through the transformer and each module (no_grad)
+        let features = no_grad(|| self.bert.forward_t(features));
+        let features = no_grad(|| {
+            self.modules
+                .iter()
+                .fold(features, |features, module| module.forward_t(features))
+        });
 
-        ids.push(Tensor::from_slice(&input_ids));
-        types.push(Tensor::from_slice(&token_type_ids));
-        masks.push(Tensor::from_slice(&input_mask));
+        // 3) extract the [batch, dim] sentence_embedding
+        features
+            .sentence_embedding
+            .unwrap()
+            .to_kind(Kind::Double)
+            .to(Device::Cpu)
+    }
+
+    /// 1) builds batched feature tensors, padded to the longest text
+    fn token_features<T: AsRef<[i64]>>(&self, tokens: &[T]) -> Features {
+        let max_len = tokens.iter().map(|t| t.as_ref().len()).max().unwrap_or(0);
+
+        let mut ids = Vec::with_capacity(tokens.len());
+        let mut types = Vec::with_capacity(tokens.len());
+        let mut masks = Vec::with_capacity(tokens.len());
+
+        for sentence_tokens in tokens {
+            let (input_ids, token_type_ids, input_mask, _len) = self
+                .bert
+                .get_sentence_features(sentence_tokens.as_ref(), max_len);
+            ids.push(Tensor::from_slice(&input_ids));
+            types.push(Tensor::from_slice(&token_type_ids));
+            masks.push(Tensor::from_slice(&input_mask));
+        }
 
         let device = self.bert.vs.device();
         let mut features = Features::default();
         features.input_ids = Some(Tensor::stack(&ids, 0).to(device));
         features.token_type_ids = Some(Tensor::stack(&types, 0).to(device));
         features.input_mask = Some(Tensor::stack(&masks, 0).to(device));
+        features
+    }
+}
 
-        // 2) forward passes (no_grad)
-        let features = no_grad(|| self.bert.forward_t(features));
-        let features = no_grad(|| self.pooling.forward_t(features));
-
-        // 3) extract the final sentence_embedding, turn it into Vec<f64>
-        let sent = features
-            .sentence_embedding
-            .unwrap() // take ownership of the Tensor
-            .to_kind(Kind::Double); // convert from f32→f64
-        Vec::<f64>::from(sent) // now From<Tensor> for Vec<f64> is available
+/// The model path, the size and modification time of the transformer
+/// weights, and a hash of the settings that shape the embeddings: the
+/// effective `max_seq_length` and lower-casing, `modules.json` and the JSON
+/// configs of the modules after the transformer. Replacing the weights in
+/// place or changing any of those yields a new id and invalidates cached
+/// embeddings. Query and document prefixes are part of the embedded text,
+/// so they key the cache already.
+fn model_id(
+    model_path: &Path,
+    transformer_path: &Path,
+    modules_config: &ModulesConfig,
+    max_seq_length: usize,
+    do_lower_case: bool,
+) -> String {
+    let mut hasher = Sha256::new();
+    hasher.update(format!("{}:{}:", max_seq_length, do_lower_case));
+    hasher.update(serde_json::to_vec(modules_config).unwrap_or_default());
+    for module in &modules_config.modules[1..] {
+        let mut config_paths: Vec<PathBuf> = fs::read_dir(model_path.join(&module.path))
+            .into_iter()
+            .flatten()
+            .flatten()
+            .map(|entry| entry.path())
+            .filter(|path| {
+                path.extension()
+                    .is_some_and(|extension| extension == "json")
+            })
+            .collect();
+        config_paths.sort();
+        for path in config_paths {
+            hasher.update(path.to_string_lossy().as_bytes());
+            hasher.update(fs::read(&path).unwrap_or_default());
+        }
     }
+    let settings = format!("{:x}", hasher.finalize());
+    let model_id = format!("{}#{}", model_path.display(), &settings[..16]);
+
+    let Some(weights_path) = checkpoint::weights_file(transformer_path) else {
+        return model_id;
+    };
+    let Ok(metadata) = fs::metadata(weights_path) else {
+        return model_id;
+    };
+    let modified = metadata
+        .modified()
+        .ok()
+        .and_then(|time| time.duration_since(UNIX_EPOCH).ok())
+        .map_or(0, |duration| duration.as_secs());
+    format!("{}@{}-{}", model_id, metadata.len(), modified)
 }
 
-pub fn generate_emdedding(text: &str) -> Vec<f64> {
-    let device = Device::cuda_if_available();
-    // unwrap here (or propagate error if you prefer a Result return type)
-    let svc =
-        SentenceTransformer::new(Path::new("models/bert-base-nli-mean-tokens"), device).unwrap();
-    svc.encode(text)
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn model_ids_change_with_the_embedding_settings() {
+        let model_path = std::env::temp_dir().join(format!("vdb-model-id-{}", std::process::id()));
+        let _ = fs::remove_dir_all(&model_path);
+        fs::create_dir_all(model_path.join("1_Pooling")).unwrap();
+        let pooling = model_path.join("1_Pooling").join("config.json");
+        fs::write(&pooling, r#"{"pooling_mode_mean_tokens": true}"#).unwrap();
+        let modules_config = ModulesConfig::load(&model_path).unwrap();
+        let id = |max_seq_length, do_lower_case| {
+            model_id(
+                &model_path,
+                &model_path,
+                &modules_config,
+                max_seq_length,
+                do_lower_case,
+            )
+        };
+
+        let base = id(128, true);
+        assert_eq!(id(128, true), base);
+        assert_ne!(id(256, true), base);
+        assert_ne!(id(128, false), base);
+        fs::write(&pooling, r#"{"pooling_mode_cls_token": true}"#).unwrap();
+        assert_ne!(id(128, true), base);
+        fs::remove_dir_all(&model_path).unwrap();
+    }
 }
@@ -0,0 +1,169 @@
+use crate::embeddings::Features;
+use crate::embeddings::checkpoint::read_json;
+use crate::embeddings::dense::Dense;
+use crate::embeddings::pooling::{Pooling, PoolingConfig};
+use rust_bert::Config;
+use serde::{Deserialize, Serialize};
+use std::path::{Path, PathBuf};
+use tch::{Device, TchError, nn};
+
+/// One entry of a sentence-transformers `modules.json`.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct ModuleConfig {
+    pub idx: usize,
+    pub name: String,
+    pub path: String,
+    #[serde(rename = "type")]
+    pub module_type: String,
+}
+
+impl ModuleConfig {
+    fn new(idx: usize, path: &str, module_type: &str) -> ModuleConfig {
+        ModuleConfig {
+            idx,
+            name: idx.to_string(),
+            path: path.to_string(),
+            module_type: module_type.to_string(),
+        }
+    }
+
+    /// The class name without its package, e.g. `Pooling` for
+    /// `sentence_transformers.models.Pooling`.
+    pub fn kind(&self) -> &str {
+        self.module_type
+            .rsplit('.')
+            .next()
+            .unwrap_or(&self.module_type)
+    }
+}
+
+/// The module pipeline of a sentence-transformers checkpoint, in run order.
+/// The first module is always the transformer.
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(transparent)]
+pub struct ModulesConfig {
+    pub modules: Vec<ModuleConfig>,
+}
+
+impl Config for ModulesConfig {}
+
+impl ModulesConfig {
+    /// Reads `modules.json` from `model_path`. Checkpoints without one get
+    /// the original two-stage layout: `0_BERT` (or the model root) followed
+    /// by `1_Pooling`.
+    pub fn load(model_path: &Path) -> Result<ModulesConfig, TchError> {
+        let modules_path = model_path.join("modules.json");
+        let mut config = if modules_path.exists() {
+            read_json(&modules_path)?
+        } else {
+            let transformer_path = if model_path.join("0_BERT").is_dir() {
+                "0_BERT"
+            } else {
+                ""
+            };
+            ModulesConfig {
+                modules: vec![
+                    ModuleConfig::new(
+                        0,
+                        transformer_path,
+                        "sentence_transformers.models.Transformer",
+                    ),
+                    ModuleConfig::new(1, "1_Pooling", "sentence_transformers.models.Pooling"),
+                ],
+            }
+        };
+        config.modules.sort_by_key(|module| module.idx);
+
+        match config.modules.first() {
+            Some(first) if first.kind() == "Transformer" => {}
+            _ => {
+                return Err(TchError::FileFormat(format!(
+                    "{} does not start with a Transformer module",
+                    modules_path.display()
+                )));
+            }
+        }
+        Ok(config)
+    }
+
+    /// Directory holding the transformer weights, tokenizer and config.
+    pub fn transformer_path(&self, model_path: &Path) -> PathBuf {
+        model_path.join(&self.modules[0].path)
+    }
+
+    /// Instantiates every module after the transformer, in order.
+    pub fn build(
+        &self,
+        model_path: &Path,
+        p: &nn::Path,
+        device: Device,
+    ) -> Result<Vec<Module>, TchError> {
+        self.modules[1..]
+            .iter()
+            .map(|module| Module::new(module, &model_path.join(&module.path), p, device))
+            .collect()
+    }
+}
+
+/// A stage applied to the transformer output.
+pub enum Module {
+    Pooling(Pooling),
+    Dense(Dense),
+    Normalize,
+}
+
+impl Module {
+    fn new(
+        config: &ModuleConfig,
+        module_path: &Path,
+        p: &nn::Path,
+        device: Device,
+    ) -> Result<Module, TchError> {
+        match config.kind() {
+            "Pooling" => Ok(Module::Pooling(Pooling::new(
+                &(p / "pooling"),
+                &read_json::<PoolingConfig>(&module_path.join("config.json"))?,
+            ))),
+            "Dense" => Ok(Module::Dense(Dense::new(module_path, device)?)),
+            "Normalize" => Ok(Module::Normalize),
+            "Transformer" => Err(TchError::FileFormat(format!(
+                "module {} is a second Transformer; only one is supported",
+                config.name
+            ))),
+            _ => Err(TchError::FileFormat(format!(
+                "unsupported module type `{}` for module {} ({})",
+                config.module_type,
+                config.name,
+                module_path.display()
+            ))),
+        }
+    }
+
+    /// Size of the sentence embedding this module produces, or `None` when
+    /// it keeps the incoming size.
+    pub fn output_dimension(&self) -> Option<usize> {
+        match self {
+            Module::Pooling(pooling) => Some(pooling.output_dimension()),
+            Module::Dense(dense) => Some(dense.output_dimension()),
+            Module::Normalize => None,
+        }
+    }
+
+    pub fn forward_t(&self, features: Features) -> Features {
+        match self {
+            Module::Pooling(pooling) => pooling.forward_t(features),
+            Module::Dense(dense) => dense.forward_t(features),
+            Module::Normalize => {
+                let sentence_embedding = features.sentence_embedding.as_ref().unwrap();
+                let norm = sentence_embedding
+                    .norm_scalaropt_dim(2, [1], true)
+                    .clamp_min(1e-12);
+                let normalized = sentence_embedding / norm;
+                Features {
+                    sentence_embedding: Some(normalized),
+                    ..features
+                }
+            }
+        }
+    }
+}
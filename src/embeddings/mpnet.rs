@@ -0,0 +1,268 @@
+use rust_bert::Config;
+use serde::{Deserialize, Serialize};
+use std::borrow::Borrow;
+use tch::{Kind, Tensor, nn};
+
+/// MPNet encoder configuration, as found in the `config.json` of
+/// `microsoft/mpnet-base` derived checkpoints such as `all-mpnet-base-v2`.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct MPNetConfig {
+    pub vocab_size: i64,
+    pub hidden_size: i64,
+    pub num_hidden_layers: i64,
+    pub num_attention_heads: i64,
+    pub intermediate_size: i64,
+    pub hidden_dropout_prob: f64,
+    pub attention_probs_dropout_prob: f64,
+    pub max_position_embeddings: i64,
+    pub layer_norm_eps: Option<f64>,
+    pub relative_attention_num_buckets: Option<i64>,
+    pub pad_token_id: Option<i64>,
+}
+
+impl Config for MPNetConfig {}
+
+/// Largest relative distance that still gets its own attention bias bucket.
+const RELATIVE_ATTENTION_MAX_DISTANCE: i64 = 128;
+
+/// Encoder-only MPNet: BERT-style layers with a learned relative position
+/// bias shared by every attention layer. rust-bert does not provide MPNet,
+/// so it is built here from `tch::nn` with Hugging Face parameter names.
+pub struct MPNetModel {
+    embeddings: MPNetEmbeddings,
+    layers: Vec<MPNetLayer>,
+    relative_attention_bias: nn::Embedding,
+    num_buckets: i64,
+}
+
+impl MPNetModel {
+    pub fn new<'p, P>(p: P, config: &MPNetConfig) -> MPNetModel
+    where
+        P: Borrow<nn::Path<'p>>,
+    {
+        let p = p.borrow();
+        let num_buckets = config.relative_attention_num_buckets.unwrap_or(32);
+        let encoder = p / "encoder";
+        let layers = (0..config.num_hidden_layers)
+            .map(|i| MPNetLayer::new(&encoder / "layer" / i, config))
+            .collect();
+        let relative_attention_bias = nn::embedding(
+            &encoder / "relative_attention_bias",
+            num_buckets,
+            config.num_attention_heads,
+            Default::default(),
+        );
+
+        MPNetModel {
+            embeddings: MPNetEmbeddings::new(p / "embeddings", config),
+            layers,
+            relative_attention_bias,
+            num_buckets,
+        }
+    }
+
+    /// Returns the last hidden state, `[batch, sequence, hidden]`.
+    pub fn forward_t(&self, input_ids: &Tensor, mask: &Tensor, train: bool) -> Tensor {
+        let sequence_length = input_ids.size()[1];
+        let mut hidden_state = self.embeddings.forward_t(input_ids, train);
+
+        // [batch, 1, 1, seq]: large negative bias on padding positions
+        let mask_bias = (1.0 - mask.to_kind(Kind::Float).unsqueeze(1).unsqueeze(2)) * -10000.0;
+        // [1, heads, seq, seq]
+        let position_bias = self
+            .relative_position_buckets(sequence_length)
+            .to(input_ids.device())
+            .apply(&self.relative_attention_bias)
+            .permute([2, 0, 1])
+            .unsqueeze(0);
+        let attention_bias = position_bias + mask_bias;
+
+        for layer in &self.layers {
+            hidden_state = layer.forward_t(&hidden_state, &attention_bias, train);
+        }
+        hidden_state
+    }
+
+    fn relative_position_buckets(&self, sequence_length: i64) -> Tensor {
+        let buckets: Vec<i64> = (0..sequence_length)
+            .flat_map(|query| {
+                (0..sequence_length).map(move |key| {
+                    relative_position_bucket(
+                        key - query,
+                        self.num_buckets,
+                        RELATIVE_ATTENTION_MAX_DISTANCE,
+                    )
+                })
+            })
+            .collect();
+        Tensor::from_slice(&buckets).view([sequence_length, sequence_length])
+    }
+}
+
+/// Bidirectional T5-style bucketing: exact buckets for small distances,
+/// logarithmically wider ones up to `max_distance`.
+fn relative_position_bucket(relative_position: i64, num_buckets: i64, max_distance: i64) -> i64 {
+    let num_buckets = num_buckets / 2;
+    let n = -relative_position;
+    let offset = if n < 0 { num_buckets } else { 0 };
+    let n = n.abs();
+    let max_exact = num_buckets / 2;
+    if n < max_exact {
+        return offset + n;
+    }
+    let scale = (num_buckets - max_exact) as f64;
+    let large = max_exact
+        + ((n as f64 / max_exact as f64).ln() / (max_distance as f64 / max_exact as f64).ln()
+            * scale) as i64;
+    offset + large.min(num_buckets - 1)
+}
+
+struct MPNetEmbeddings {
+    word_embeddings: nn::Embedding,
+    position_embeddings: nn::Embedding,
+    layer_norm: nn::LayerNorm,
+    padding_idx: i64,
+    dropout: f64,
+}
+
+impl MPNetEmbeddings {
+    fn new<'p, P>(p: P, config: &MPNetConfig) -> MPNetEmbeddings
+    where
+        P: Borrow<nn::Path<'p>>,
+    {
+        let p = p.borrow();
+        let padding_idx = config.pad_token_id.unwrap_or(1);
+        let embedding_config = nn::EmbeddingConfig {
+            padding_idx,
+            ..Default::default()
+        };
+        MPNetEmbeddings {
+            word_embeddings: nn::embedding(
+                p / "word_embeddings",
+                config.vocab_size,
+                config.hidden_size,
+                embedding_config,
+            ),
+            position_embeddings: nn::embedding(
+                p / "position_embeddings",
+                config.max_position_embeddings,
+                config.hidden_size,
+                embedding_config,
+            ),
+            layer_norm: layer_norm(p / "LayerNorm", config),
+            padding_idx,
+            dropout: config.hidden_dropout_prob,
+        }
+    }
+
+    fn forward_t(&self, input_ids: &Tensor, train: bool) -> Tensor {
+        // Positions count from padding_idx + 1 and padding keeps padding_idx.
+        let not_padding = input_ids.ne(self.padding_idx).to_kind(Kind::Int64);
+        let position_ids = not_padding.cumsum(1, Kind::Int64) * &not_padding + self.padding_idx;
+
+        let embeddings =
+            input_ids.apply(&self.word_embeddings) + position_ids.apply(&self.position_embeddings);
+        embeddings
+            .apply(&self.layer_norm)
+            .dropout(self.dropout, train)
+    }
+}
+
+struct MPNetLayer {
+    q: nn::Linear,
+    k: nn::Linear,
+    v: nn::Linear,
+    o: nn::Linear,
+    attention_layer_norm: nn::LayerNorm,
+    intermediate: nn::Linear,
+    output: nn::Linear,
+    output_layer_norm: nn::LayerNorm,
+    num_heads: i64,
+    head_dim: i64,
+    attention_dropout: f64,
+    hidden_dropout: f64,
+}
+
+impl MPNetLayer {
+    fn new<'p, P>(p: P, config: &MPNetConfig) -> MPNetLayer
+    where
+        P: Borrow<nn::Path<'p>>,
+    {
+        let p = p.borrow();
+        let attention = p / "attention";
+        let attn = &attention / "attn";
+        let hidden = config.hidden_size;
+        MPNetLayer {
+            q: nn::linear(&attn / "q", hidden, hidden, Default::default()),
+            k: nn::linear(&attn / "k", hidden, hidden, Default::default()),
+            v: nn::linear(&attn / "v", hidden, hidden, Default::default()),
+            o: nn::linear(&attn / "o", hidden, hidden, Default::default()),
+            attention_layer_norm: layer_norm(&attention / "LayerNorm", config),
+            intermediate: nn::linear(
+                p / "intermediate" / "dense",
+                hidden,
+                config.intermediate_size,
+                Default::default(),
+            ),
+            output: nn::linear(
+                p / "output" / "dense",
+                config.intermediate_size,
+                hidden,
+                Default::default(),
+            ),
+            output_layer_norm: layer_norm(p / "output" / "LayerNorm", config),
+            num_heads: config.num_attention_heads,
+            head_dim: hidden / config.num_attention_heads,
+            attention_dropout: config.attention_probs_dropout_prob,
+            hidden_dropout: config.hidden_dropout_prob,
+        }
+    }
+
+    fn forward_t(&self, hidden_state: &Tensor, attention_bias: &Tensor, train: bool) -> Tensor {
+        let size = hidden_state.size();
+        let (batch_size, sequence_length) = (size[0], size[1]);
+        let split_heads = |x: Tensor| {
+            x.view([batch_size, sequence_length, self.num_heads, self.head_dim])
+                .transpose(1, 2)
+        };
+
+        let q = split_heads(hidden_state.apply(&self.q));
+        let k = split_heads(hidden_state.apply(&self.k));
+        let v = split_heads(hidden_state.apply(&self.v));
+
+        let scores =
+            q.matmul(&k.transpose(-1, -2)) / (self.head_dim as f64).sqrt() + attention_bias;
+        let probabilities = scores
+            .softmax(-1, Kind::Float)
+            .dropout(self.attention_dropout, train);
+        let context = probabilities.matmul(&v).transpose(1, 2).contiguous().view([
+            batch_size,
+            sequence_length,
+            self.num_heads * self.head_dim,
+        ]);
+        let attention_output = (context.apply(&self.o).dropout(self.hidden_dropout, train)
+            + hidden_state)
+            .apply(&self.attention_layer_norm);
+
+        let intermediate = attention_output.apply(&self.intermediate).gelu("none");
+        (intermediate
+            .apply(&self.output)
+            .dropout(self.hidden_dropout, train)
+            + attention_output)
+            .apply(&self.output_layer_norm)
+    }
+}
+
+fn layer_norm<'p, P>(p: P, config: &MPNetConfig) -> nn::LayerNorm
+where
+    P: Borrow<nn::Path<'p>>,
+{
+    nn::layer_norm(
+        p,
+        vec![config.hidden_size],
+        nn::LayerNormConfig {
+            eps: config.layer_norm_eps.unwrap_or(1e-12),
+            ..Default::default()
+        },
+    )
+}
@@ -11,9 +11,11 @@ pub struct PoolingConfig {
     pub pooling_mode_max_tokens: Option<bool>,
     pub pooling_mode_mean_tokens: Option<bool>,
     pub pooling_mode_mean_sqrt_len_tokens: Option<bool>,
+    pub pooling_mode_weightedmean_tokens: Option<bool>,
+    pub pooling_mode_lasttoken: Option<bool>,
 }
 
-impl Config<PoolingConfig> for PoolingConfig {}
+impl Config for PoolingConfig {}
 
 #[derive(Debug)]
 pub struct Pooling {
@@ -21,6 +23,8 @@ pub struct Pooling {
     pooling_mode_max_tokens: bool,
     pooling_mode_mean_tokens: bool,
     pooling_mode_mean_sqrt_len_tokens: bool,
+    pooling_mode_weightedmean_tokens: bool,
+    pooling_mode_lasttoken: bool,
     pooling_output_dimension: i16,
 }
 
@@ -49,10 +53,30 @@ impl Pooling {
                 false
             };
 
-        let pooling_mode_multiplier: i16 = (pooling_mode_cls_token
-            || pooling_mode_max_tokens
-            || pooling_mode_mean_tokens
-            || pooling_mode_mean_sqrt_len_tokens) as i16;
+        let pooling_mode_weightedmean_tokens =
+            if let Some(value) = config.pooling_mode_weightedmean_tokens {
+                value
+            } else {
+                false
+            };
+        let pooling_mode_lasttoken = if let Some(value) = config.pooling_mode_lasttoken {
+            value
+        } else {
+            false
+        };
+
+        // forward_t concatenates one vector per enabled mode
+        let pooling_mode_multiplier = [
+            pooling_mode_cls_token,
+            pooling_mode_max_tokens,
+            pooling_mode_mean_tokens,
+            pooling_mode_mean_sqrt_len_tokens,
+            pooling_mode_weightedmean_tokens,
+            pooling_mode_lasttoken,
+        ]
+        .iter()
+        .filter(|enabled| **enabled)
+        .count() as i16;
         let pooling_output_dimension = pooling_mode_multiplier * config.word_embedding_dimension;
 
         Pooling {
@@ -60,13 +84,35 @@ impl Pooling {
             pooling_mode_max_tokens,
             pooling_mode_mean_tokens,
             pooling_mode_mean_sqrt_len_tokens,
+            pooling_mode_weightedmean_tokens,
+            pooling_mode_lasttoken,
             pooling_output_dimension,
         }
     }
 
+    pub fn output_dimension(&self) -> usize {
+        self.pooling_output_dimension as usize
+    }
+
     pub fn forward_t(&self, features: Features) -> Features {
         let mut output_vectors: Vec<Tensor> = Vec::new();
 
+        let token_embeddings = features.token_embeddings.as_ref().unwrap();
+        let input_mask = features.input_mask.as_ref().unwrap();
+        let mask = input_mask
+            .unsqueeze(-1)
+            .expand(token_embeddings.size(), false)
+            .to_kind(Kind::Float);
+
+        // Number of (weighted) tokens per text, [batch]. Earlier modules may
+        // supply their own weights; otherwise every unmasked token counts once.
+        let token_weights_sum = match &features.token_weights_sum {
+            Some(w) => w.shallow_clone(),
+            None => input_mask
+                .to_kind(Kind::Float)
+                .sum_dim_intlist(1, false, Kind::Float),
+        };
+
         if self.pooling_mode_cls_token {
             let cls = features
                 .cls_token_embeddings
@@ -77,46 +123,21 @@ impl Pooling {
         }
 
         if self.pooling_mode_max_tokens {
-            let mask = features
-                .input_mask
-                .as_ref()
-                .unwrap()
-                .unsqueeze(-1)
-                .expand(&features.token_embeddings.as_ref().unwrap().size(), false)
-                .to_kind(Kind::Float);
+            let min_value = Tensor::from_slice(&[f32::MIN]).to_device(token_embeddings.device());
+            let masked_embeddings = token_embeddings.where_self(&mask.ne(0), &min_value);
 
-            let min_value = Tensor::of_slice(&[f32::MIN]);
-            let token_embeddings = features
-                .token_embeddings
-                .as_ref()
-                .unwrap()
-                .where1(&mask.ne(0), &min_value);
-
-            let max_over_time = token_embeddings.max_dim(1, false).0;
+            let max_over_time = masked_embeddings.max_dim(1, false).0;
             output_vectors.push(max_over_time);
         }
 
         // 3) MEAN / MEAN_SQRT_LEN pooling
         if self.pooling_mode_mean_tokens || self.pooling_mode_mean_sqrt_len_tokens {
-            let mask = features
-                .input_mask
-                .as_ref()
-                .unwrap()
+            let sum_embeddings = (token_embeddings * &mask).sum_dim_intlist(1, false, Kind::Float);
+
+            let sum_mask = token_weights_sum
                 .unsqueeze(-1)
-                .expand(&features.token_embeddings.as_ref().unwrap().size(), false)
-                .to_kind(Kind::Float);
-
-            let token_emb = features.token_embeddings.as_ref().unwrap();
-            let sum_embeddings = (token_emb * &mask).sum_dim_intlist(&[1], false, Kind::Float);
-
-            let sum_mask = match &features.token_weights_sum {
-                Some(w) => w
-                    .shallow_clone()
-                    .unsqueeze(-1)
-                    .expand(&sum_embeddings.size(), false),
-                None => mask.sum_dim_intlist(&[1], false, Kind::Float),
-            }
-            .clamp_min(1e-9);
+                .expand(sum_embeddings.size(), false)
+                .clamp_min(1e-9);
 
             if self.pooling_mode_mean_tokens {
                 output_vectors.push(&sum_embeddings / &sum_mask);
@@ -126,10 +147,44 @@ impl Pooling {
             }
         }
 
+        // 4) WEIGHTED MEAN: token i (1-based) is weighted by i, so later
+        // tokens count more, as used by GPT-style SGPT models
+        if self.pooling_mode_weightedmean_tokens {
+            let sequence_length = token_embeddings.size()[1];
+            let weights = Tensor::arange_start(
+                1,
+                sequence_length + 1,
+                (Kind::Float, token_embeddings.device()),
+            )
+            .view([1, sequence_length, 1]);
+            let weighted_mask = &mask * weights;
+
+            let sum_embeddings =
+                (token_embeddings * &weighted_mask).sum_dim_intlist(1, false, Kind::Float);
+            let sum_mask = weighted_mask
+                .sum_dim_intlist(1, false, Kind::Float)
+                .clamp_min(1e-9);
+            output_vectors.push(sum_embeddings / sum_mask);
+        }
+
+        // 5) LAST TOKEN: the last unmasked token of each (right-padded) text
+        if self.pooling_mode_lasttoken {
+            let hidden_size = token_embeddings.size()[2];
+            let last_index = (input_mask.sum_dim_intlist(1, false, Kind::Int64) - 1)
+                .clamp_min(0)
+                .view([-1, 1, 1])
+                .expand([-1, 1, hidden_size], false);
+            let last_token = token_embeddings
+                .gather(1, &last_index, false)
+                .squeeze_dim(1);
+            output_vectors.push(last_token);
+        }
+
         let output_vector = Tensor::cat(&output_vectors, 1);
 
         Features {
             sentence_embedding: Some(output_vector),
+            token_weights_sum: Some(token_weights_sum),
             ..features
         }
     }
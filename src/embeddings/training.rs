@@ -0,0 +1,247 @@
+use crate::embeddings::SentenceTransformer;
+use crate::embeddings::checkpoint::RUST_WEIGHTS_FILE;
+use crate::embeddings::modules::ModulesConfig;
+use rand::SeedableRng;
+use rand::rngs::StdRng;
+use rand::seq::SliceRandom;
+use std::collections::{HashSet, VecDeque};
+use std::path::Path;
+use tch::nn::{self, OptimizerConfig, VarStore};
+use tch::{Kind, TchError, Tensor};
+
+/// Settings for `fine_tune`. The defaults follow sentence-transformers'
+/// `MultipleNegativesRankingLoss` recipe.
+#[derive(Debug, Clone)]
+pub struct TrainingConfig {
+    pub epochs: usize,
+    /// Pairs per step. The other positives in a batch are the negatives for
+    /// each query, so larger batches make a harder objective.
+    pub batch_size: usize,
+    /// Peak learning rate of AdamW.
+    pub learning_rate: f64,
+    /// AdamW weight decay, not applied to biases and LayerNorm weights.
+    pub weight_decay: f64,
+    /// Steps over which the learning rate rises linearly from zero; it then
+    /// decays linearly back to zero at the last step.
+    pub warmup_steps: usize,
+    /// Multiplies the cosine similarities before the softmax.
+    pub scale: f64,
+    /// Gradients are clipped to this L2 norm.
+    pub max_grad_norm: f64,
+    /// Seed for shuffling the pairs every epoch.
+    pub seed: u64,
+}
+
+impl Default for TrainingConfig {
+    fn default() -> Self {
+        TrainingConfig {
+            epochs: 1,
+            batch_size: 32,
+            learning_rate: 2e-5,
+            weight_decay: 0.01,
+            warmup_steps: 100,
+            scale: 20.0,
+            max_grad_norm: 1.0,
+            seed: 42,
+        }
+    }
+}
+
+/// Fine-tunes the transformer of `model` on `(query, positive)` pairs with
+/// in-batch negatives: each query is trained to score its own positive
+/// above every other positive in the batch (`MultipleNegativesRankingLoss`).
+///
+/// Only the transformer weights in `model.bert.vs` are updated; pooling has
+/// no weights and `Dense` modules stay frozen. Pairs sharing a positive are
+/// kept out of the same batch, where each would be a negative of the other.
+/// Returns the mean loss of each epoch, and fails on a `batch_size` below
+/// 2, which leaves no negatives.
+///
+/// The model id is not updated, so embeddings cached for the model must be
+/// dropped or the model reloaded after `save`.
+pub fn fine_tune(
+    model: &SentenceTransformer,
+    pairs: &[(String, String)],
+    config: &TrainingConfig,
+) -> Result<Vec<f64>, TchError> {
+    if config.batch_size < 2 {
+        return Err(TchError::Kind(
+            "in-batch negatives need a batch size of at least 2".to_string(),
+        ));
+    }
+    let excluded = exclude_from_weight_decay(&model.bert.vs);
+    let mut optimizer = nn::AdamW {
+        wd: config.weight_decay,
+        ..Default::default()
+    }
+    .build(&model.bert.vs, config.learning_rate)?;
+    if excluded > 0 {
+        optimizer.set_weight_decay_group(NO_DECAY_GROUP, 0.0);
+    }
+
+    let mut rng = StdRng::seed_from_u64(config.seed);
+    let mut order: Vec<usize> = (0..pairs.len()).collect();
+    let epochs: Vec<Vec<Vec<usize>>> = (0..config.epochs)
+        .map(|_| {
+            order.shuffle(&mut rng);
+            // A batch of one pair has no negatives and is skipped.
+            let mut batches = batches(&order, pairs, config.batch_size);
+            batches.retain(|batch| batch.len() >= 2);
+            batches
+        })
+        .collect();
+    let total_steps = epochs.iter().map(Vec::len).sum();
+
+    let mut epoch_losses = Vec::with_capacity(config.epochs);
+    let mut step = 0;
+    for batches in &epochs {
+        let mut epoch_loss = 0.0;
+        for batch in batches {
+            let learning_rate =
+                config.learning_rate * learning_rate_factor(step, total_steps, config);
+            optimizer.set_lr(learning_rate);
+
+            let queries: Vec<&str> = batch.iter().map(|&i| pairs[i].0.as_str()).collect();
+            let positives: Vec<&str> = batch.iter().map(|&i| pairs[i].1.as_str()).collect();
+            let loss = ranking_loss(
+                &embed_train(model, &queries),
+                &embed_train(model, &positives),
+                config.scale,
+            );
+            optimizer.backward_step_clip_norm(&loss, config.max_grad_norm);
+
+            epoch_loss += loss.double_value(&[]);
+            step += 1;
+        }
+        epoch_losses.push(epoch_loss / batches.len().max(1) as f64);
+    }
+    Ok(epoch_losses)
+}
+
+/// Writes the transformer weights of `model` to `rust_model.ot` in the
+/// transformer directory of `model_path`, where loading picks them up ahead
+/// of any other checkpoint format.
+pub fn save(model: &SentenceTransformer, model_path: &Path) -> Result<(), TchError> {
+    let transformer_path = ModulesConfig::load(model_path)?.transformer_path(model_path);
+    model.bert.vs.save(transformer_path.join(RUST_WEIGHTS_FILE))
+}
+
+/// Optimizer parameter group of the variables trained without weight decay.
+const NO_DECAY_GROUP: usize = 1;
+
+/// Moves biases and LayerNorm weights of `vs` to `NO_DECAY_GROUP`, as
+/// sentence-transformers does, and returns how many were moved.
+fn exclude_from_weight_decay(vs: &VarStore) -> usize {
+    let mut variables = vs.variables_.lock().unwrap();
+    let no_decay: HashSet<usize> = variables
+        .named_variables
+        .iter()
+        .filter(|(name, _)| {
+            let name = name.to_lowercase();
+            name.ends_with(".bias") || name.contains("layernorm") || name.contains("layer_norm")
+        })
+        .map(|(_, tensor)| tensor.data_ptr() as usize)
+        .collect();
+    let mut moved = 0;
+    for variable in variables.trainable_variables.iter_mut() {
+        if no_decay.contains(&(variable.tensor.data_ptr() as usize)) {
+            variable.group = NO_DECAY_GROUP;
+            moved += 1;
+        }
+    }
+    moved
+}
+
+/// Splits `order` into batches of up to `batch_size` pairs with distinct
+/// positives. A pair whose positive is already in the batch being filled
+/// moves on to the next one, like sentence-transformers'
+/// `NoDuplicatesDataLoader`.
+fn batches(order: &[usize], pairs: &[(String, String)], batch_size: usize) -> Vec<Vec<usize>> {
+    let mut queue: VecDeque<usize> = order.iter().copied().collect();
+    let mut batches = vec![];
+    while !queue.is_empty() {
+        let mut batch = Vec::with_capacity(batch_size);
+        let mut positives = HashSet::new();
+        let mut deferred = vec![];
+        while batch.len() < batch_size
+            && let Some(i) = queue.pop_front()
+        {
+            if positives.insert(pairs[i].1.as_str()) {
+                batch.push(i);
+            } else {
+                deferred.push(i);
+            }
+        }
+        for i in deferred.into_iter().rev() {
+            queue.push_front(i);
+        }
+        batches.push(batch);
+    }
+    batches
+}
+
+/// Linear warmup to 1, then linear decay to 0.
+fn learning_rate_factor(step: usize, total_steps: usize, config: &TrainingConfig) -> f64 {
+    if step < config.warmup_steps {
+        return (step + 1) as f64 / config.warmup_steps as f64;
+    }
+    let remaining = total_steps.saturating_sub(step) as f64;
+    remaining / total_steps.saturating_sub(config.warmup_steps).max(1) as f64
+}
+
+/// `[batch, dim]` sentence embeddings with dropout and gradients enabled.
+fn embed_train(model: &SentenceTransformer, texts: &[&str]) -> Tensor {
+    let tokens = model.bert.tokenize_multithreaded(texts.to_vec());
+    let features = model.bert.forward_train(model.token_features(&tokens));
+    model
+        .modules
+        .iter()
+        .fold(features, |features, module| module.forward_t(features))
+        .sentence_embedding
+        .unwrap()
+}
+
+/// Cross entropy of the scaled cosine similarity matrix against the
+/// diagonal, where row `i` holds query `i` against every positive.
+fn ranking_loss(queries: &Tensor, positives: &Tensor, scale: f64) -> Tensor {
+    let scores = normalize(queries).matmul(&normalize(positives).tr()) * scale;
+    let labels = Tensor::arange(scores.size()[0], (Kind::Int64, scores.device()));
+    scores.cross_entropy_for_logits(&labels)
+}
+
+fn normalize(embeddings: &Tensor) -> Tensor {
+    embeddings / embeddings.norm_scalaropt_dim(2, [1], true).clamp_min(1e-12)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn pairs(positives: &[&str]) -> Vec<(String, String)> {
+        positives
+            .iter()
+            .enumerate()
+            .map(|(i, positive)| (format!("query {}", i), positive.to_string()))
+            .collect()
+    }
+
+    #[test]
+    fn batches_keep_duplicate_positives_apart() {
+        let pairs = pairs(&["a", "a", "b", "c", "a", "d"]);
+        let order: Vec<usize> = (0..pairs.len()).collect();
+        let batches = batches(&order, &pairs, 3);
+        assert_eq!(batches, vec![vec![0, 2, 3], vec![1, 5], vec![4]]);
+    }
+
+    #[test]
+    fn learning_rate_warms_up_and_decays() {
+        let config = TrainingConfig {
+            warmup_steps: 2,
+            ..TrainingConfig::default()
+        };
+        assert_eq!(learning_rate_factor(0, 6, &config), 0.5);
+        assert_eq!(learning_rate_factor(1, 6, &config), 1.0);
+        assert_eq!(learning_rate_factor(2, 6, &config), 1.0);
+        assert_eq!(learning_rate_factor(5, 6, &config), 0.25);
+    }
+}
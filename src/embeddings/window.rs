@@ -0,0 +1,101 @@
+use crate::embeddings::SentenceTransformer;
+use tch::{Kind, Tensor};
+
+/// How the embeddings of overlapping windows are combined into one vector.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum WindowAggregation {
+    /// Average of the window embeddings.
+    #[default]
+    Mean,
+    /// Element-wise maximum over the windows.
+    Max,
+    /// Average weighted by the number of tokens in each window, so a short
+    /// trailing window counts less.
+    LengthWeighted,
+}
+
+/// Settings for `SentenceTransformer::encode_windowed`, also used by
+/// collections that embed documents with `CollectionConfig::window`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct SlidingWindow {
+    /// Tokens shared by consecutive windows; must be less than the model's
+    /// `max_seq_length`.
+    pub overlap: usize,
+    pub aggregation: WindowAggregation,
+}
+
+impl Default for SlidingWindow {
+    fn default() -> Self {
+        SlidingWindow {
+            overlap: 32,
+            aggregation: WindowAggregation::Mean,
+        }
+    }
+}
+
+impl SentenceTransformer {
+    /// Embeds `text` without truncating it: token sequences longer than
+    /// `max_seq_length` are cut into overlapping windows that run through
+    /// the model as one batch, and the window embeddings are aggregated into
+    /// a single vector. Short texts give the same result as `encode`.
+    ///
+    /// Fails if `window.overlap` is not less than `max_seq_length`, which
+    /// would leave no room for the windows to advance.
+    pub fn encode_windowed(&self, text: &str, window: &SlidingWindow) -> Result<Vec<f64>, String> {
+        let max_seq_length = self.bert.max_seq_length();
+        if window.overlap >= max_seq_length {
+            return Err(format!(
+                "Window overlap {} must be less than max_seq_length {}",
+                window.overlap, max_seq_length
+            ));
+        }
+        let tokens = self.bert.tokenize(text);
+        let windows = windows(&tokens, max_seq_length, window.overlap);
+        let embeddings = self.encode_tokens(&windows);
+
+        let aggregated = match window.aggregation {
+            WindowAggregation::Mean => embeddings.mean_dim(0, false, Kind::Double),
+            WindowAggregation::Max => embeddings.max_dim(0, false).0,
+            WindowAggregation::LengthWeighted => {
+                let lengths: Vec<f64> = windows.iter().map(|w| w.len().max(1) as f64).collect();
+                let weights = Tensor::from_slice(&lengths).unsqueeze(1);
+                (embeddings * &weights).sum_dim_intlist(0, false, Kind::Double)
+                    / weights.sum(Kind::Double)
+            }
+        };
+        Ok(Vec::<f64>::try_from(aggregated).unwrap())
+    }
+}
+
+/// Splits `tokens` into windows of at most `size` tokens, each starting
+/// `size - overlap` tokens after the previous one. Always returns at least
+/// one (possibly empty) window.
+fn windows(tokens: &[i64], size: usize, overlap: usize) -> Vec<&[i64]> {
+    let size = size.max(1);
+    let step = size.saturating_sub(overlap).max(1);
+    let mut windows = vec![];
+    let mut start = 0;
+    loop {
+        let end = (start + size).min(tokens.len());
+        windows.push(&tokens[start..end]);
+        if end == tokens.len() {
+            return windows;
+        }
+        start += step;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn windows_overlap_and_cover_every_token() {
+        let tokens: Vec<i64> = (0..10).collect();
+        assert_eq!(
+            windows(&tokens, 4, 1),
+            [&tokens[0..4], &tokens[3..7], &tokens[6..10]]
+        );
+        assert_eq!(windows(&[], 4, 1), [&[] as &[i64]]);
+    }
+}
@@ -1,38 +1,52 @@
 pub mod database;
 pub mod embeddings;
+pub mod util;
 
-use crate::database::DatabaseOperations;
+use crate::database::{CollectionConfig, DatabaseOperations, Preprocessing};
 use anyhow::Result;
-use embeddings::SentenceTransformer;
+use embeddings::{EmbeddingConfig, SentenceTransformer, TrainingConfig, training};
 use polars::prelude::*;
 use std::fs::File;
-use std::path::Path;
+use std::sync::{Arc, Mutex};
 use std::time::Instant;
 
+const DATA_PATH: &str = "./data/data_cleaned.tsv";
+
 pub fn new(method: &str) -> Result<SentenceTransformer> {
-    let path = "./data/data_cleaned.tsv";
-    let file = File::open(path).expect("could not open file");
+    let file = File::open(DATA_PATH).expect("could not open file");
 
     let data = CsvReader::new(file).finish().unwrap();
 
     let row_count = data.shape().0;
 
     let texts = get_texts(&data, "column_2".to_string());
-    let references = texts.clone();
+    // Stored texts come back preprocessed, so compare against the same form.
+    let preprocessing = dataset_preprocessing();
+    let references: Vec<String> = texts.iter().map(|t| preprocessing.apply(t)).collect();
 
-    let mut db = database::new(method);
+    let config = CollectionConfig {
+        preprocessing,
+        ..CollectionConfig::default()
+    };
+    // The database shares the model, which is handed back once it is done.
+    let embedding_config = EmbeddingConfig::from_env().map_err(anyhow::Error::msg)?;
+    let model = Arc::new(Mutex::new(SentenceTransformer::from_config(
+        &embedding_config,
+    )?));
+    let embedder = database::cached_embedder(model.clone(), &embedding_config)?;
+    let db = database::with_embedder(method, embedder, config);
 
     let start_time = Instant::now();
-    db.load(&texts);
+    db.load(&texts).map_err(anyhow::Error::msg)?;
 
     let queries = get_texts(&data, "column_1".to_string());
 
+    let results = db.query_batch(&queries, 1);
+
     let mut correct = 0;
     for i in 0..row_count {
-        let query = queries[i].as_str().to_string();
-        let query_array = &db.query(query, 1);
-        if query_array.len() > 0 {
-            let query_result = query_array[0].text.as_str().to_string();
+        if results[i].len() > 0 {
+            let query_result = results[i][0].text.as_str().to_string();
             if &query_result == &references[i] {
                 correct += 1;
             }
@@ -43,11 +57,55 @@ pub fn new(method: &str) -> Result<SentenceTransformer> {
         "RESULTS: |{}| had correct |{}|, out of |{}|, in |{:?}|",
         method, correct, row_count, elapsed
     );
-    let path = Path::new(method);
-    let svc = SentenceTransformer::new(path, tch::Device::Cpu)?;
+    drop(db);
+    let svc = Arc::into_inner(model)
+        .expect("the database no longer holds the model")
+        .into_inner()
+        .unwrap();
     Ok(svc)
 }
 
+/// Fine-tunes the configured model on the (query, reference) pairs that
+/// `new` evaluates on and saves the weights back to the model directory.
+pub fn fine_tune(training_config: &TrainingConfig) -> Result<()> {
+    let file = File::open(DATA_PATH).expect("could not open file");
+    let data = CsvReader::new(file).finish().unwrap();
+    let queries = get_texts(&data, "column_1".to_string());
+    let references = get_texts(&data, "column_2".to_string());
+    let preprocessing = dataset_preprocessing();
+    let pairs: Vec<(String, String)> = queries
+        .iter()
+        .zip(&references)
+        .map(|(query, reference)| (preprocessing.apply(query), preprocessing.apply(reference)))
+        .collect();
+
+    let config = EmbeddingConfig::from_env().map_err(anyhow::Error::msg)?;
+    let svc = SentenceTransformer::from_config(&config)?;
+
+    let start_time = Instant::now();
+    let losses = training::fine_tune(&svc, &pairs, training_config)?;
+    for (epoch, loss) in losses.iter().enumerate() {
+        println!("epoch {}: loss {:.4}", epoch + 1, loss);
+    }
+    training::save(&svc, &config.model_dir)?;
+    println!(
+        "trained on |{}| pairs in |{:?}|, saved to {}",
+        pairs.len(),
+        start_time.elapsed(),
+        config.model_dir.display()
+    );
+    Ok(())
+}
+
+/// `get_texts` reads cells through `AnyValue::to_string`, which wraps them
+/// in quotes.
+fn dataset_preprocessing() -> Preprocessing {
+    Preprocessing {
+        strip_quotes: true,
+        ..Preprocessing::standard()
+    }
+}
+
 fn get_texts(data: &DataFrame, column: String) -> Vec<String> {
     let row_count = data.shape().0;
     let text_col = data.select(&[column.to_string()]).unwrap();
@@ -1,5 +1,28 @@
 use uuid::Uuid;
 
 pub fn get_uuid() -> String {
-  Uuid::new_v4.to_string() 
+    Uuid::new_v4().to_string()
+}
+
+pub fn get_uuid_v7() -> String {
+    Uuid::now_v7().to_string()
+}
+
+/// Byte ranges of the whitespace separated words of `text`.
+pub fn word_spans(text: &str) -> Vec<(usize, usize)> {
+    let mut spans = vec![];
+    let mut start = None;
+    for (i, c) in text.char_indices() {
+        if c.is_whitespace() {
+            if let Some(s) = start.take() {
+                spans.push((s, i));
+            }
+        } else if start.is_none() {
+            start = Some(i);
+        }
+    }
+    if let Some(s) = start {
+        spans.push((s, text.len()));
+    }
+    spans
 }